@@ -0,0 +1,430 @@
+use super::xml::{Attr, XMLElem};
+use std::collections::BTreeSet;
+
+// <style amp-custom>とインラインのstyle属性を合わせたCSSの上限(バイト)
+pub const CSS_LIMIT: usize = 75_000;
+const INLINE_STYLE_LIMIT: usize = 1_000;
+
+// AMPで使えない要素と、代わりに使うもの
+const DISALLOWED_TAGS: [(&str, &str); 13] = [
+    ("img", "use amp-img"),
+    ("video", "use amp-video"),
+    ("audio", "use amp-audio"),
+    ("iframe", "use amp-iframe"),
+    ("base", "base is prohibited"),
+    ("frame", "frames are prohibited"),
+    ("frameset", "frames are prohibited"),
+    ("object", "plugins are prohibited"),
+    ("param", "plugins are prohibited"),
+    ("applet", "plugins are prohibited"),
+    ("embed", "plugins are prohibited"),
+    ("style", "only <style amp-custom> in head is allowed"),
+    ("math", "MathML is not supported. use \\math or \\equation"),
+];
+
+// AMPランタイムに含まれ、拡張スクリプトが要らない要素
+const BUILTIN_COMPONENTS: [&str; 3] = ["amp-img", "amp-pixel", "amp-layout"];
+
+// 属性として使われ、拡張スクリプトが要るもの
+const COMPONENT_ATTRS: [(&str, &str); 1] = [("lightbox", "amp-lightbox-gallery")];
+
+// ツリー中で使われているAMPの拡張コンポーネントを集める
+pub fn used_components(elems: &[XMLElem], components: &mut BTreeSet<String>) {
+    for elem in elems {
+        let (name, attrs, inner) = match elem {
+            XMLElem::Single(name, attrs) => (name, attrs, &[][..]),
+            XMLElem::WithElem(name, attrs, inner) => (name, attrs, inner.as_slice()),
+            _ => continue,
+        };
+        if name.starts_with("amp-") && !BUILTIN_COMPONENTS.contains(&name.as_str()) {
+            components.insert(name.to_owned());
+        }
+        for attr in attrs {
+            let attr = attr_name(attr);
+            if let Some((_, component)) = COMPONENT_ATTRS.iter().find(|(a, _)| *a == attr) {
+                components.insert((*component).to_owned());
+            }
+        }
+        used_components(inner, components);
+    }
+}
+
+type AttrPred<'a> = &'a dyn Fn(&[Attr]) -> bool;
+
+// 大きさを持たないamp-imgのレイアウト
+const SIZELESS_LAYOUTS: [&str; 3] = ["fill", "flex-item", "nodisplay"];
+
+fn attr<'a>(attrs: &'a [Attr], name: &str) -> Option<&'a str> {
+    attrs.iter().find_map(|attr| match attr {
+        Attr::Pair(n, v) if n == name => Some(v.as_str()),
+        Attr::Single(n) if n == name => Some(""),
+        _ => None,
+    })
+}
+
+fn attr_name(attr: &Attr) -> &str {
+    match attr {
+        Attr::Pair(name, _) | Attr::Single(name) => name,
+    }
+}
+
+fn check_attrs(name: &str, attrs: &[Attr], in_foreign: bool, violations: &mut Vec<String>) {
+    for a in attrs {
+        let attr = attr_name(a);
+        if attr.len() > 2 && attr.starts_with("on") {
+            violations.push(format!(
+                "event handler attribute {} on <{}> is not allowed",
+                attr, name
+            ));
+        }
+        if attr.starts_with("xml:") || (attr == "xmlns" && !in_foreign) {
+            violations.push(format!("attribute {} on <{}> is not allowed", attr, name));
+        }
+    }
+    if let Some(href) = attr(attrs, "href") {
+        if href.trim_start().to_lowercase().starts_with("javascript:") {
+            violations.push(format!("javascript: URL in <{}> is not allowed", name));
+        }
+    }
+    if let Some(style) = attr(attrs, "style") {
+        if style.contains("!important") {
+            violations.push(format!("!important in style of <{}> is not allowed", name));
+        }
+        if style.len() > INLINE_STYLE_LIMIT {
+            violations.push(format!(
+                "style of <{}> is {} bytes (limit {})",
+                name,
+                style.len(),
+                INLINE_STYLE_LIMIT
+            ));
+        }
+    }
+}
+
+fn check_amp_img(attrs: &[Attr], violations: &mut Vec<String>) {
+    let src = attr(attrs, "src").or_else(|| attr(attrs, "srcset"));
+    let desc = src.map(|src| format!(" ({})", src)).unwrap_or_default();
+    if src.is_none() {
+        violations.push("<amp-img> requires src or srcset".to_owned());
+    }
+    match attr(attrs, "layout") {
+        None => violations.push(format!("<amp-img>{} requires layout", desc)),
+        Some(layout) if SIZELESS_LAYOUTS.contains(&layout) => return,
+        _ => (),
+    }
+    for name in &["width", "height"] {
+        if attr(attrs, name).is_none() {
+            violations.push(format!("<amp-img>{} requires {}", desc, name));
+        }
+    }
+}
+
+// 1つの要素を調べ、子も調べるならその時点でsvgの中かどうかを返す
+pub fn check_node(
+    name: &str,
+    attrs: &[Attr],
+    in_foreign: bool,
+    violations: &mut Vec<String>,
+) -> Option<bool> {
+    // headはcheck_pageで調べる
+    if name == "head" {
+        return None;
+    }
+    if let Some((_, reason)) = DISALLOWED_TAGS.iter().find(|(tag, _)| *tag == name) {
+        violations.push(format!("<{}> is not allowed. {}", name, reason));
+    }
+    if name == "script" && attr(attrs, "type") != Some("application/ld+json") {
+        violations.push("<script> is not allowed except for JSON-LD".to_owned());
+    }
+    if name == "link" && attr(attrs, "rel") == Some("stylesheet") {
+        violations.push("external stylesheets are not allowed".to_owned());
+    }
+    if name == "amp-img" {
+        check_amp_img(attrs, violations);
+    }
+    let in_foreign = in_foreign || name == "svg";
+    check_attrs(name, attrs, in_foreign, violations);
+    Some(in_foreign)
+}
+
+fn check_impl(elem: &XMLElem, in_foreign: bool, violations: &mut Vec<String>) {
+    let (name, attrs, inner) = match elem {
+        XMLElem::Single(name, attrs) => (name.as_str(), attrs.as_slice(), &[][..]),
+        XMLElem::WithElem(name, attrs, inner) => {
+            (name.as_str(), attrs.as_slice(), inner.as_slice())
+        }
+        _ => return,
+    };
+    if let Some(in_foreign) = check_node(name, attrs, in_foreign, violations) {
+        for child in inner {
+            check_impl(child, in_foreign, violations);
+        }
+    }
+}
+
+// 要素と属性の制限を調べる。head以外の要素が対象
+pub fn check_elem(elem: &XMLElem) -> Vec<String> {
+    let mut violations = Vec::new();
+    check_impl(elem, false, &mut violations);
+    violations
+}
+
+fn children(elem: &XMLElem) -> &[XMLElem] {
+    match elem {
+        XMLElem::WithElem(_, _, inner) => inner,
+        _ => &[],
+    }
+}
+
+fn text(elem: &XMLElem) -> String {
+    children(elem)
+        .iter()
+        .map(|child| match child {
+            XMLElem::Text(text) | XMLElem::Raw(text) => text.to_owned(),
+            _ => String::new(),
+        })
+        .collect()
+}
+
+fn find<'a>(elems: &'a [XMLElem], name: &str, pred: AttrPred) -> Option<&'a XMLElem> {
+    elems.iter().find(|elem| match elem {
+        XMLElem::Single(n, attrs) | XMLElem::WithElem(n, attrs, _) => n == name && pred(attrs),
+        _ => false,
+    })
+}
+
+fn inline_styles(elem: &XMLElem) -> usize {
+    match elem {
+        XMLElem::Single(_, attrs) => attr(attrs, "style").map(str::len).unwrap_or(0),
+        XMLElem::WithElem(_, attrs, inner) => {
+            attr(attrs, "style").map(str::len).unwrap_or(0)
+                + inner.iter().map(inline_styles).sum::<usize>()
+        }
+        _ => 0,
+    }
+}
+
+// ページ全体の制限(必須のhead要素、拡張コンポーネントのスクリプト、CSSの大きさ)を調べる
+pub fn check_page(html: &XMLElem) -> Vec<String> {
+    let mut violations = Vec::new();
+    let html_attrs = match html {
+        XMLElem::WithElem(name, attrs, _) if name == "html" => attrs,
+        _ => return vec!["root element must be <html>".to_owned()],
+    };
+    if attr(html_attrs, "amp").is_none() && attr(html_attrs, "⚡").is_none() {
+        violations.push("<html> requires the amp attribute".to_owned());
+    }
+    let head = match find(children(html), "head", &|_| true) {
+        Some(head) => children(head),
+        None => return vec!["<head> is missing".to_owned()],
+    };
+    let is_charset =
+        |attrs: &[Attr]| attr(attrs, "charset").map(str::to_lowercase) == Some("utf-8".to_owned());
+    if find(&head[..head.len().min(1)], "meta", &is_charset).is_none() {
+        violations.push("<meta charset=\"utf-8\"> must be the first child of <head>".to_owned());
+    }
+    let required: [(&str, &str, AttrPred); 4] = [
+        ("meta", "<meta name=\"viewport\">", &|attrs| {
+            attr(attrs, "name") == Some("viewport")
+        }),
+        ("link", "<link rel=\"canonical\">", &|attrs| {
+            attr(attrs, "rel") == Some("canonical")
+        }),
+        ("script", "the AMP runtime script", &|attrs| {
+            attr(attrs, "src") == Some("https://cdn.ampproject.org/v0.js")
+                && attr(attrs, "async").is_some()
+        }),
+        ("style", "<style amp-boilerplate>", &|attrs| {
+            attr(attrs, "amp-boilerplate").is_some()
+        }),
+    ];
+    for (name, desc, pred) in required.iter() {
+        if find(head, name, *pred).is_none() {
+            violations.push(format!("{} is missing in <head>", desc));
+        }
+    }
+    let noscript_boilerplate = find(head, "noscript", &|_| true)
+        .and_then(|noscript| {
+            find(children(noscript), "style", &|attrs| {
+                attr(attrs, "amp-boilerplate").is_some()
+            })
+        })
+        .is_some();
+    if !noscript_boilerplate {
+        violations.push("<noscript><style amp-boilerplate> is missing in <head>".to_owned());
+    }
+    for elem in head {
+        match elem {
+            XMLElem::WithElem(name, attrs, _) | XMLElem::Single(name, attrs)
+                if name == "script" =>
+            {
+                let allowed = attr(attrs, "src") == Some("https://cdn.ampproject.org/v0.js")
+                    || attr(attrs, "custom-element").is_some()
+                    || attr(attrs, "custom-template").is_some()
+                    || attr(attrs, "type") == Some("application/ld+json");
+                if !allowed {
+                    violations.push("<script> in <head> is not an AMP script".to_owned());
+                }
+            }
+            _ => (),
+        }
+    }
+    let mut components = BTreeSet::new();
+    for elem in children(html) {
+        if !matches!(elem, XMLElem::WithElem(name, _, _) if name == "head") {
+            used_components(std::slice::from_ref(elem), &mut components);
+        }
+    }
+    for component in components {
+        if find(head, "script", &|attrs| {
+            attr(attrs, "custom-element") == Some(component.as_str())
+        })
+        .is_none()
+        {
+            violations.push(format!(
+                "<script custom-element=\"{}\"> is missing in <head>",
+                component
+            ));
+        }
+    }
+    let custom_css = find(head, "style", &|attrs| attr(attrs, "amp-custom").is_some())
+        .map(|style| text(style).len())
+        .unwrap_or(0);
+    let css_size = custom_css + inline_styles(html);
+    if css_size > CSS_LIMIT {
+        violations.push(format!(
+            "CSS is {} bytes (<style amp-custom> {} bytes and inline styles {} bytes), exceeding the limit of {} bytes",
+            css_size,
+            custom_css,
+            css_size - custom_css,
+            CSS_LIMIT
+        ));
+    }
+    violations
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pair(name: &str, value: &str) -> Attr {
+        Attr::Pair(name.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn test_check_elem() {
+        let body = XMLElem::WithElem(
+            "div".to_owned(),
+            vec![pair("onclick", "f()")],
+            vec![
+                XMLElem::Single("img".to_owned(), vec![pair("src", "a.png")]),
+                XMLElem::Single(
+                    "amp-img".to_owned(),
+                    vec![pair("src", "a.png"), pair("layout", "responsive")],
+                ),
+                XMLElem::Single(
+                    "amp-img".to_owned(),
+                    vec![pair("src", "a.png"), pair("layout", "fill")],
+                ),
+                XMLElem::WithElem(
+                    "math".to_owned(),
+                    vec![pair("xmlns", "http://www.w3.org/1998/Math/MathML")],
+                    vec![],
+                ),
+                XMLElem::WithElem("a".to_owned(), vec![pair("href", "javascript:f()")], vec![]),
+            ],
+        );
+        assert_eq!(
+            check_elem(&body),
+            vec![
+                "event handler attribute onclick on <div> is not allowed",
+                "<img> is not allowed. use amp-img",
+                "<amp-img> (a.png) requires width",
+                "<amp-img> (a.png) requires height",
+                "<math> is not allowed. MathML is not supported. use \\math or \\equation",
+                "attribute xmlns on <math> is not allowed",
+                "javascript: URL in <a> is not allowed",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_used_components() {
+        let body = vec![xml!(div [] [
+            XMLElem::WithElem("amp-img".to_owned(), vec![], vec![]),
+            XMLElem::WithElem("amp-accordion".to_owned(), vec![], vec![
+                XMLElem::Single("amp-iframe".to_owned(), vec![]),
+            ]),
+            xml!(p [class="amp-img-container"] []),
+            XMLElem::Single("amp-img".to_owned(), vec![Attr::Single("lightbox".to_owned())])
+        ])];
+        let mut components = BTreeSet::new();
+        used_components(&body, &mut components);
+        assert_eq!(
+            components.into_iter().collect::<Vec<_>>(),
+            vec!["amp-accordion", "amp-iframe", "amp-lightbox-gallery"]
+        );
+    }
+
+    #[test]
+    fn test_check_page() {
+        let head = vec![
+            XMLElem::Single("meta".to_owned(), vec![pair("charset", "UTF-8")]),
+            XMLElem::WithElem(
+                "script".to_owned(),
+                vec![
+                    Attr::Single("async".to_owned()),
+                    pair("src", "https://cdn.ampproject.org/v0.js"),
+                ],
+                vec![],
+            ),
+            XMLElem::Single("link".to_owned(), vec![pair("rel", "canonical")]),
+            XMLElem::Single("meta".to_owned(), vec![pair("name", "viewport")]),
+            XMLElem::WithElem(
+                "style".to_owned(),
+                vec![Attr::Single("amp-custom".to_owned())],
+                vec![XMLElem::Text("a".repeat(CSS_LIMIT))],
+            ),
+            XMLElem::WithElem(
+                "style".to_owned(),
+                vec![Attr::Single("amp-boilerplate".to_owned())],
+                vec![],
+            ),
+            XMLElem::WithElem(
+                "script".to_owned(),
+                vec![pair("custom-element", "amp-video")],
+                vec![],
+            ),
+        ];
+        let body = XMLElem::WithElem(
+            "p".to_owned(),
+            vec![pair("style", "color: red")],
+            vec![
+                XMLElem::Single("amp-video".to_owned(), vec![]),
+                XMLElem::Single(
+                    "amp-img".to_owned(),
+                    vec![Attr::Single("lightbox".to_owned())],
+                ),
+            ],
+        );
+        let html = XMLElem::WithElem(
+            "html".to_owned(),
+            vec![Attr::Single("amp".to_owned())],
+            vec![
+                XMLElem::WithElem("head".to_owned(), vec![], head),
+                XMLElem::WithElem("body".to_owned(), vec![], vec![body]),
+            ],
+        );
+        let violations = check_page(&html);
+        assert_eq!(violations.len(), 3);
+        assert_eq!(
+            violations[0],
+            "<noscript><style amp-boilerplate> is missing in <head>"
+        );
+        assert_eq!(
+            violations[1],
+            "<script custom-element=\"amp-lightbox-gallery\"> is missing in <head>"
+        );
+        assert!(violations[2].starts_with("CSS is 75010 bytes"));
+    }
+}
@@ -1,12 +1,22 @@
-use super::convert::Context;
-use super::{Cmd, Location, Parsed, TextElemAst};
+use super::bib::{self, Bibliography};
+use super::config::{self, Config, PlaceholderStyle};
+use super::convert::{CitationStyle, Context, Numbering};
+use super::images;
+use super::og;
+use super::remote;
+use super::{Cmd, Location, Parsed, TextElem, TextElemAst};
 use super::{Error, Value};
 use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone};
 use image::GenericImageView;
+use log::warn;
+use rusttype::Font;
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io;
 use std::path::{Path, PathBuf};
-use syntect::parsing::SyntaxSet;
+use syntect::highlighting::{FontStyle, Theme, ThemeSet};
+use syntect::parsing::{SyntaxDefinition, SyntaxSet};
 
 type ArticleHeading = (PathBuf, Vec<TextElemAst>);
 
@@ -21,12 +31,31 @@ pub struct Report {
     per_article: HashMap<PathBuf, ArticleInfo>,
     titles: HashMap<PathBuf, Vec<(PathBuf, Vec<TextElemAst>)>>,
     aspects: HashMap<PathBuf, (usize, usize)>,
+    bibliographies: HashMap<PathBuf, Bibliography>,
+    csv_tables: HashMap<PathBuf, Vec<Vec<String>>>,
+    snippets: HashMap<PathBuf, String>,
+    numbering: HashMap<PathBuf, Numbering>,
+    remote_sizes: remote::Lock,
+    placeholders: HashMap<PathBuf, images::Placeholder>,
+    og_images: HashMap<PathBuf, String>,
+    media: HashSet<PathBuf>,
     ss: SyntaxSet,
+    pub config: Config,
     pub category_pages: HashMap<String, Vec<(PathBuf, Vec<TextElemAst>)>>,
     pub css: String,
+    pub og_cards: Vec<(PathBuf, og::Card)>,
+    // \galleryに置かれ、一覧用の縮小版を作る画像
+    pub thumbnails: HashSet<PathBuf>,
+    pub transcoded: HashSet<PathBuf>,
+    pub og_fonts: Vec<Font<'static>>,
 }
 
 impl Report {
+    // \blockcodeのfile=で読み込まれたファイルか
+    pub fn is_snippet(&self, path: &Path) -> bool {
+        self.snippets.contains_key(path)
+    }
+
     pub fn get_context<'a>(&'a self, p: &'a Path) -> Option<Context<'a>> {
         if let Some((loc, prev, next, sha256)) = &self.per_article.get(p) {
             Some(Context {
@@ -37,10 +66,23 @@ impl Report {
                 titles: &self.titles,
                 categories: &self.category_pages,
                 ss: &self.ss,
+                config: &self.config,
                 sha256: Some(sha256),
                 path: p,
                 aspects: &self.aspects,
                 css: &self.css,
+                sidenote: false,
+                bibliography: self.bibliographies.get(p),
+                citation_style: CitationStyle::Numeric,
+                csv_tables: &self.csv_tables,
+                snippets: &self.snippets,
+                numbering: self.numbering.get(p),
+                remote_sizes: &self.remote_sizes,
+                placeholders: &self.placeholders,
+                transcoded: &self.transcoded,
+                media: &self.media,
+                og_image: self.og_images.get(p).map(String::as_str),
+                page: Default::default(),
             })
         } else {
             None
@@ -56,18 +98,153 @@ impl Report {
             titles: &self.titles,
             categories: &self.category_pages,
             ss: &self.ss,
+            config: &self.config,
             sha256: None,
             path: p,
             aspects: &self.aspects,
             css: &self.css,
+            sidenote: false,
+            bibliography: None,
+            citation_style: CitationStyle::Numeric,
+            csv_tables: &self.csv_tables,
+            snippets: &self.snippets,
+            numbering: None,
+            remote_sizes: &self.remote_sizes,
+            placeholders: &self.placeholders,
+            transcoded: &self.transcoded,
+            media: &self.media,
+            og_image: None,
+            page: Default::default(),
+        }
+    }
+}
+
+// syntectのcss_for_themeと同等だが、クラス名に接頭辞を付ける。
+// 明るいテーマの背景色はindex.cssのpreに任せる
+fn theme_css(theme: &Theme, prefix: &str, with_background: bool) -> String {
+    let mut css = format!(".{}code{{", prefix);
+    if let Some(fg) = theme.settings.foreground {
+        css.push_str(&format!("color:#{:02x}{:02x}{:02x};", fg.r, fg.g, fg.b));
+    }
+    if let Some(bg) = theme.settings.background.filter(|_| with_background) {
+        css.push_str(&format!(
+            "background-color:#{:02x}{:02x}{:02x};",
+            bg.r, bg.g, bg.b
+        ));
+    }
+    css.push('}');
+    for item in &theme.scopes {
+        for selector in &item.scope.selectors {
+            for scope in selector.extract_scopes() {
+                let class = scope
+                    .build_string()
+                    .split('.')
+                    .map(|atom| format!(".{}{}", prefix, atom))
+                    .collect::<String>();
+                css.push_str(&class);
+                css.push('{');
+                if let Some(fg) = item.style.foreground {
+                    css.push_str(&format!("color:#{:02x}{:02x}{:02x};", fg.r, fg.g, fg.b));
+                }
+                if let Some(bg) = item.style.background {
+                    css.push_str(&format!(
+                        "background-color:#{:02x}{:02x}{:02x};",
+                        bg.r, bg.g, bg.b
+                    ));
+                }
+                if let Some(fs) = item.style.font_style {
+                    if fs.contains(FontStyle::UNDERLINE) {
+                        css.push_str("text-decoration:underline;");
+                    }
+                    if fs.contains(FontStyle::BOLD) {
+                        css.push_str("font-weight:bold;");
+                    }
+                    if fs.contains(FontStyle::ITALIC) {
+                        css.push_str("font-style:italic;");
+                    }
+                }
+                css.push('}');
+            }
+        }
+    }
+    css
+}
+
+const TML_SYNTAX: &str = include_str!("../syntaxes/tml.sublime-syntax");
+
+// syntect組み込みのシンタックスに同梱のTMLとサイト内の.sublime-syntaxを加える
+fn load_syntaxes(parsed: &Parsed) -> Result<SyntaxSet, Error> {
+    let mut builder = SyntaxSet::load_defaults_nonewlines().into_builder();
+    let tml = SyntaxDefinition::load_from_str(TML_SYNTAX, false, None)
+        .map_err(|e| Error::Internal(format!("cannot load bundled TML syntax ({:?})", e)))?;
+    builder.add(tml);
+    let mut paths = parsed
+        .keys()
+        .filter(|path| path.extension() == Some(OsStr::new("sublime-syntax")))
+        .collect::<Vec<_>>();
+    // 同名の定義がある場合に結果が変わらないよう順序を固定する
+    paths.sort();
+    for path in paths {
+        if let Some(super::File::Blob(src)) = parsed.get(path) {
+            let name = path.file_stem().and_then(|stem| stem.to_str());
+            let syntax =
+                SyntaxDefinition::load_from_str(&String::from_utf8_lossy(src), false, name)
+                    .map_err(|e| Error::ConfigError {
+                        path: path.to_owned(),
+                        desc: format!("cannot load syntax definition ({:?})", e),
+                    })?;
+            builder.add(syntax);
+        }
+    }
+    Ok(builder.build())
+}
+
+// syntect組み込みのテーマにサイト内の.tmThemeを加える。名前はファイル名から拡張子を除いたもの
+fn load_themes(parsed: &Parsed) -> Result<BTreeMap<String, Theme>, Error> {
+    let mut themes = ThemeSet::load_defaults().themes;
+    for (path, file) in parsed {
+        if path.extension() != Some(OsStr::new("tmTheme")) {
+            continue;
+        }
+        if let super::File::Blob(src) = file {
+            let theme = ThemeSet::load_from_reader(&mut io::Cursor::new(src)).map_err(|e| {
+                Error::ConfigError {
+                    path: path.to_owned(),
+                    desc: format!("cannot load theme ({:?})", e),
+                }
+            })?;
+            let name = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| Error::CannotInterpretPathAsUTF8(path.to_owned()))?;
+            themes.insert(name.to_owned(), theme);
         }
     }
+    Ok(themes)
 }
 
-pub fn generate_syntect_css() -> String {
-    let ts = syntect::highlighting::ThemeSet::load_defaults();
-    let light_theme = &ts.themes["InspiredGitHub"];
-    syntect::html::css_for_theme(light_theme)
+pub fn generate_syntect_css(
+    config: &Config,
+    themes: &BTreeMap<String, Theme>,
+) -> Result<String, Error> {
+    let get_theme = |name: &str| {
+        themes.get(name).ok_or_else(|| Error::ConfigError {
+            path: PathBuf::from(config::CONFIG_FILE),
+            desc: format!(
+                "no such theme {}. available themes are {}",
+                name,
+                themes.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        })
+    };
+    let prefix = &config.highlight.class_prefix;
+    let mut css = theme_css(get_theme(&config.highlight.theme)?, prefix, false);
+    if let Some(dark_theme) = &config.highlight.dark_theme {
+        css.push_str("@media (prefers-color-scheme: dark){");
+        css.push_str(&theme_css(get_theme(dark_theme)?, prefix, true));
+        css.push('}');
+    }
+    Ok(css)
 }
 
 fn extract_title(cmd: &(Cmd, Location)) -> Result<Vec<TextElemAst>, Error> {
@@ -92,7 +269,7 @@ fn extract_date(cmd: &(Cmd, Location)) -> Result<DateTime<FixedOffset>, Error> {
             } else {
                 Err(Error::InvalidAttributeType {
                     name: "date".to_owned(),
-                    loc: loc.to_owned(),
+                    loc: Box::new(loc.to_owned()),
                     expected: crate::ValueType::Str,
                     found: data_val.value_type(),
                 })
@@ -169,6 +346,336 @@ fn prevs_and_nexts(parsed: &Parsed) -> Result<(Prevs, Nexts, Titles), Error> {
     Ok((prevs, nexts, titles))
 }
 
+// サイト全体で共有される参考文献
+const SITE_BIBLIOGRAPHY: &str = "bibliography.bib";
+
+fn walk_value<'a, F>(value: &'a Value, f: &mut F) -> Result<(), Error>
+where
+    F: FnMut(&'a Cmd, &'a Location) -> Result<(), Error>,
+{
+    match value {
+        Value::Text(text) => walk_text(text, f),
+        Value::List(list) => list.iter().try_for_each(|(v, _)| walk_value(v, f)),
+        _ => Ok(()),
+    }
+}
+
+fn walk_text<'a, F>(text: &'a [TextElemAst], f: &mut F) -> Result<(), Error>
+where
+    F: FnMut(&'a Cmd, &'a Location) -> Result<(), Error>,
+{
+    for (elem, loc) in text {
+        if let TextElem::Cmd(cmd) = elem {
+            walk_cmd(cmd, loc, f)?;
+        }
+    }
+    Ok(())
+}
+
+// コマンドを文書順に辿る(属性値のテキストも含む)
+fn walk_cmd<'a, F>(cmd: &'a Cmd, loc: &'a Location, f: &mut F) -> Result<(), Error>
+where
+    F: FnMut(&'a Cmd, &'a Location) -> Result<(), Error>,
+{
+    f(cmd, loc)?;
+    let mut attrs = cmd.attrs.iter().collect::<Vec<_>>();
+    attrs.sort_by_key(|(name, _)| name.to_owned());
+    for (_, (value, _)) in attrs {
+        walk_value(value, f)?;
+    }
+    walk_text(&cmd.inner, f)
+}
+
+// 図表・数式・コードのリストに文書順で番号を振る。変換時の番号と\refの表示はこれを使う
+fn number_cmds(cmd: &(Cmd, Location)) -> Result<Numbering, Error> {
+    let mut numbering = Numbering::default();
+    let mut counters: HashMap<&str, usize> = HashMap::new();
+    walk_cmd(&cmd.0, &cmd.1, &mut |cmd, loc| {
+        let counter = match crate::convert::numbering_counter(cmd) {
+            Some(counter) => counters.entry(counter).or_insert(0),
+            None => return Ok(()),
+        };
+        *counter += 1;
+        let n = *counter;
+        numbering.numbers.insert(loc.to_owned(), n);
+        if let Some(id) = crate::value_utils::verify_str(&cmd.attrs, "id", loc)? {
+            let label = crate::convert::number_label(&cmd.name, n);
+            if numbering.labels.insert(id.to_owned(), label).is_some() {
+                return Err(Error::InvalidAttribute {
+                    name: "id".to_owned(),
+                    reason: format!("id \"{}\" is already used", id),
+                    loc: Box::new(loc.to_owned()),
+                });
+            }
+        }
+        Ok(())
+    })?;
+    Ok(numbering)
+}
+
+fn load_bibliography(parsed: &Parsed, path: &Path, loc: &Location) -> Result<Bibliography, Error> {
+    match parsed.get(path) {
+        Some(super::File::Blob(src)) => {
+            let src = String::from_utf8_lossy(src);
+            let fname = path
+                .to_str()
+                .ok_or_else(|| Error::CannotInterpretPathAsUTF8(path.to_owned()))?;
+            Ok(bib::parse(fname, &src)?
+                .into_iter()
+                .map(|entry| (entry.key.clone(), entry))
+                .collect())
+        }
+        _ => Err(Error::InvalidLink {
+            link: path.to_owned(),
+            msg: "bibliography not found".to_owned(),
+            loc: Box::new(loc.to_owned()),
+        }),
+    }
+}
+
+fn check_citations(cmd: &(Cmd, Location), bib: &Bibliography) -> Result<HashSet<String>, Error> {
+    let mut cited = HashSet::new();
+    walk_cmd(&cmd.0, &cmd.1, &mut |cmd, loc| {
+        if cmd.name == "cite" {
+            let key = crate::value_utils::get_str(&cmd.attrs, "key", loc)?;
+            if !bib.contains_key(key) {
+                return Err(Error::InvalidAttribute {
+                    name: "key".to_owned(),
+                    reason: format!("unknown citation key \"{}\"", key),
+                    loc: Box::new(cmd.attrs["key"].1.to_owned()),
+                });
+            }
+            cited.insert(key.to_owned());
+        }
+        Ok(())
+    })?;
+    Ok(cited)
+}
+
+fn warn_unused_entries(bib: &Bibliography, cited: &HashSet<String>) {
+    let mut unused = bib
+        .values()
+        .filter(|entry| !cited.contains(&entry.key))
+        .collect::<Vec<_>>();
+    unused.sort_by_key(|entry| entry.key.to_owned());
+    for entry in unused {
+        warn!("{} unused bibliography entry {}", entry.location, entry.key);
+    }
+}
+
+// \table csv=...で参照されるCSVを読み込み、列数の揃わない行を検出する
+fn load_csv_tables(
+    parsed: &Parsed,
+    cmd: &(Cmd, Location),
+    path: &Path,
+    tables: &mut HashMap<PathBuf, Vec<Vec<String>>>,
+) -> Result<(), Error> {
+    walk_cmd(&cmd.0, &cmd.1, &mut |cmd, loc| {
+        if cmd.name != "table" {
+            return Ok(());
+        }
+        if let Some(csv_path) = crate::value_utils::verify_str(&cmd.attrs, "csv", loc)? {
+            let csv_path = PathBuf::from(crate::convert::absolute(loc, csv_path, path)?);
+            if tables.contains_key(&csv_path) {
+                return Ok(());
+            }
+            let src = match parsed.get(&csv_path) {
+                Some(super::File::Blob(src)) => String::from_utf8_lossy(src),
+                _ => {
+                    return Err(Error::InvalidLink {
+                        link: csv_path,
+                        msg: "csv file not found".to_owned(),
+                        loc: Box::new(loc.to_owned()),
+                    })
+                }
+            };
+            let fname = csv_path
+                .to_str()
+                .ok_or_else(|| Error::CannotInterpretPathAsUTF8(csv_path.to_owned()))?;
+            let records = crate::csv::parse(fname, &src)?;
+            if let Some((head, _)) = records.first() {
+                for (record, record_loc) in &records {
+                    if record.len() != head.len() {
+                        return Err(Error::ProcessError {
+                            loc: record_loc.to_owned(),
+                            desc: format!(
+                                "row has {} columns but the table has {}",
+                                record.len(),
+                                head.len()
+                            ),
+                        });
+                    }
+                }
+            }
+            tables.insert(
+                csv_path,
+                records.into_iter().map(|(record, _)| record).collect(),
+            );
+        }
+        Ok(())
+    })
+}
+
+// \blockcode file=...で参照されるソースファイルを読み込む
+fn load_snippets(
+    parsed: &Parsed,
+    cmd: &(Cmd, Location),
+    path: &Path,
+    snippets: &mut HashMap<PathBuf, String>,
+) -> Result<(), Error> {
+    walk_cmd(&cmd.0, &cmd.1, &mut |cmd, loc| {
+        if cmd.name != "blockcode" {
+            return Ok(());
+        }
+        if let Some(file) = crate::value_utils::verify_str(&cmd.attrs, "file", loc)? {
+            let candidates = crate::convert::snippet_candidates(loc, file, path)?;
+            // .tmlはページとして.htmlのキーで読み込まれているので、そのソースを使う
+            let found = candidates.into_iter().find_map(|p| {
+                if p.extension() == Some(OsStr::new("tml")) {
+                    match parsed.get(&p.with_extension("html")) {
+                        Some(super::File::Tml(_, src)) => Some((p, src.to_owned())),
+                        _ => None,
+                    }
+                } else {
+                    match parsed.get(&p) {
+                        Some(super::File::Blob(src)) => {
+                            Some((p, String::from_utf8_lossy(src).to_string()))
+                        }
+                        _ => None,
+                    }
+                }
+            });
+            match found {
+                Some((p, src)) => {
+                    snippets.entry(p).or_insert(src);
+                }
+                None => {
+                    return Err(Error::InvalidLink {
+                        link: PathBuf::from(file),
+                        msg: "snippet file not found".to_owned(),
+                        loc: Box::new(loc.to_owned()),
+                    })
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+// fetch-imagesで大きさを取得する外部画像
+pub fn remote_image_urls(parsed: &Parsed) -> Result<BTreeSet<String>, Error> {
+    let mut urls = BTreeSet::new();
+    for file in parsed.values() {
+        if let super::File::Tml(cmd, _) = file {
+            walk_cmd(&cmd.0, &cmd.1, &mut |cmd, loc| {
+                if cmd.name == "img" {
+                    let url = crate::value_utils::get_str(&cmd.attrs, "url", loc)?;
+                    if crate::convert::is_http_url(url) {
+                        urls.insert(url.to_owned());
+                    }
+                }
+                Ok(())
+            })?;
+        }
+    }
+    Ok(urls)
+}
+
+fn collect_gallery_images(
+    cmd: &(Cmd, Location),
+    path: &Path,
+    thumbnails: &mut HashSet<PathBuf>,
+) -> Result<(), Error> {
+    walk_cmd(&cmd.0, &cmd.1, &mut |cmd, _| {
+        if cmd.name != "gallery" {
+            return Ok(());
+        }
+        for (elem, loc) in &cmd.inner {
+            if let TextElem::Cmd(img) = elem {
+                if img.name != "img" {
+                    continue;
+                }
+                let url = crate::value_utils::get_str(&img.attrs, "url", loc)?;
+                if !crate::convert::is_http_url(url) {
+                    thumbnails.insert(PathBuf::from(crate::convert::absolute(loc, url, path)?));
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+// タイトルから装飾を除いた文字列
+fn plain_text(text: &[TextElemAst]) -> String {
+    let mut plain = String::new();
+    for (elem, _) in text {
+        match elem {
+            TextElem::Plain(s) | TextElem::Str(s) => plain.push_str(s),
+            TextElem::Cmd(cmd) => plain.push_str(&plain_text(&cmd.inner)),
+        }
+    }
+    plain.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn load_og_fonts(parsed: &Parsed, config: &Config) -> Result<Vec<Font<'static>>, Error> {
+    let mut fonts = Vec::new();
+    if let Some(font_path) = &config.og_image.font {
+        let font = match parsed.get(Path::new(font_path)) {
+            Some(super::File::Blob(binary)) => Font::try_from_vec(binary.to_owned()),
+            _ => None,
+        };
+        fonts.push(font.ok_or_else(|| Error::ConfigError {
+            path: PathBuf::from(config::CONFIG_FILE),
+            desc: format!("og-image.font {} is not a font in the site", font_path),
+        })?);
+    }
+    fonts.extend(og::embedded_fonts());
+    Ok(fonts)
+}
+
+// og-image属性で指定された画像、無ければ記事のカード画像を使う
+fn og_image(
+    parsed: &Parsed,
+    config: &Config,
+    fonts: &[Font],
+    cmd: &(Cmd, Location),
+    path: &Path,
+    og_cards: &mut Vec<(PathBuf, og::Card)>,
+) -> Result<Option<String>, Error> {
+    if let Some(url) = crate::value_utils::verify_str(&cmd.0.attrs, "og-image", &cmd.1)? {
+        let absolute = crate::convert::absolute(&cmd.1, url, path)?;
+        if !crate::convert::is_http_url(url) && !parsed.contains_key(Path::new(&absolute)) {
+            return Err(Error::InvalidLink {
+                msg: "og-image not found".to_owned(),
+                loc: Box::new(cmd.1.clone()),
+                link: PathBuf::from(url),
+            });
+        }
+        return Ok(Some(absolute));
+    }
+    if cmd.0.name != "article" || !config.og_image.generate {
+        return Ok(None);
+    }
+    let card_path = og::card_path(path);
+    let card = og::Card {
+        title: plain_text(&extract_title(cmd)?),
+        date: extract_date(cmd)?.format("%Y-%m-%d").to_string(),
+        categories: extract_category(cmd)?,
+    };
+    let missing = og::missing_glyphs(fonts, &card);
+    if !missing.is_empty() {
+        return Err(Error::ProcessError {
+            loc: cmd.1.clone(),
+            desc: format!(
+                "no font for {:?} in the OG image. set og-image.font in config.json",
+                missing
+            ),
+        });
+    }
+    og_cards.push((card_path.clone(), card));
+    Ok(Some(card_path.to_string_lossy().to_string()))
+}
+
 fn calc_sha256(path: &Path, src: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(src);
@@ -181,8 +688,48 @@ pub fn analyze(parsed: &Parsed) -> Result<Report, Error> {
     let mut per_article = HashMap::new();
     let mut category_pages = HashMap::new();
     let mut aspects = HashMap::new();
+    let mut bibliographies = HashMap::new();
+    let mut csv_tables = HashMap::new();
+    let mut snippets = HashMap::new();
+    let mut numbering = HashMap::new();
+    let config_path = Path::new(config::CONFIG_FILE);
+    let config = match parsed.get(config_path) {
+        Some(super::File::Blob(src)) => config::parse(config_path, src)?,
+        _ => Config::default(),
+    };
+    let og_fonts = load_og_fonts(parsed, &config)?;
+    let mut og_images = HashMap::new();
+    let mut og_cards = Vec::new();
+    let mut thumbnails = HashSet::new();
+    let site_bib_path = Path::new(SITE_BIBLIOGRAPHY);
+    let site_bib = if parsed.contains_key(site_bib_path) {
+        load_bibliography(parsed, site_bib_path, &Location::Generated)?
+    } else {
+        HashMap::new()
+    };
+    let mut site_cited = HashSet::new();
     for (path, file) in parsed {
         if let super::File::Tml(cmd, src) = file {
+            let article_bib = if let Some(bib_path) =
+                crate::value_utils::verify_str(&cmd.0.attrs, "bibliography", &cmd.1)?
+            {
+                let bib_path = crate::convert::absolute(&cmd.1, bib_path, path)?;
+                load_bibliography(parsed, Path::new(&bib_path), &cmd.1)?
+            } else {
+                HashMap::new()
+            };
+            let merged_bib = bib::merge(&[&site_bib, &article_bib]);
+            let cited = check_citations(cmd, &merged_bib)?;
+            warn_unused_entries(&article_bib, &cited);
+            site_cited.extend(cited);
+            bibliographies.insert(path.to_owned(), merged_bib);
+            load_csv_tables(parsed, cmd, path, &mut csv_tables)?;
+            load_snippets(parsed, cmd, path, &mut snippets)?;
+            numbering.insert(path.to_owned(), number_cmds(cmd)?);
+            collect_gallery_images(cmd, path, &mut thumbnails)?;
+            if let Some(image) = og_image(parsed, &config, &og_fonts, cmd, path, &mut og_cards)? {
+                og_images.insert(path.to_owned(), image);
+            }
             for category in extract_category(cmd)? {
                 category_pages
                     .entry(category)
@@ -203,8 +750,49 @@ pub fn analyze(parsed: &Parsed) -> Result<Report, Error> {
                 path.to_owned(),
                 (img.width() as usize, img.height() as usize),
             );
+        } else if let super::File::MeasuredImage(size, _) = file {
+            aspects.insert(path.to_owned(), *size);
         }
     }
+    warn_unused_entries(&site_bib, &site_cited);
+    let placeholders = if config.images.placeholder == PlaceholderStyle::Disabled {
+        HashMap::new()
+    } else {
+        parsed
+            .iter()
+            .filter_map(|(path, file)| match file {
+                super::File::Image(img, _) => Some(
+                    images::placeholder(path, img)
+                        .map(|placeholder| (path.to_owned(), placeholder)),
+                ),
+                _ => None,
+            })
+            .collect::<Result<_, Error>>()?
+    };
+    let transcoded = if config.images.transcode_webp {
+        parsed
+            .iter()
+            .filter(|(path, file)| {
+                matches!(file, super::File::Image(..)) && images::transcoded_path(path).is_some()
+            })
+            .map(|(path, _)| path.to_owned())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+    let lock_path = Path::new(remote::LOCKFILE);
+    let remote_sizes = match parsed.get(lock_path) {
+        Some(super::File::Blob(src)) => remote::parse_lock(lock_path, src)?,
+        _ => remote::Lock::new(),
+    };
+    let media = parsed
+        .iter()
+        .filter(|(path, file)| {
+            matches!(file, super::File::Blob(_)) && crate::convert::media_type(path).is_some()
+        })
+        .map(|(path, _)| path.to_owned())
+        .collect();
+    let syntect_css = generate_syntect_css(&config, &load_themes(parsed)?)?;
     let index_css = parsed
         .get(Path::new("index.css"))
         .map(|file| match file {
@@ -214,10 +802,23 @@ pub fn analyze(parsed: &Parsed) -> Result<Report, Error> {
         .unwrap_or_else(String::new);
     Ok(Report {
         aspects,
+        bibliographies,
+        csv_tables,
+        snippets,
+        numbering,
+        remote_sizes,
+        placeholders,
+        og_images,
+        media,
+        og_cards,
+        og_fonts,
+        thumbnails,
+        transcoded,
         category_pages,
         per_article,
-        ss: SyntaxSet::load_defaults_nonewlines(),
+        ss: load_syntaxes(parsed)?,
+        config,
         titles,
-        css: index_css + &generate_syntect_css(),
+        css: index_css + &syntect_css,
     })
 }
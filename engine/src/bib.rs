@@ -0,0 +1,284 @@
+use super::{Error, Location, Position};
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Entry {
+    pub kind: String,
+    pub key: String,
+    pub fields: HashMap<String, String>,
+    pub location: Location,
+}
+
+pub type Bibliography = HashMap<String, Entry>;
+
+struct Scanner<'a> {
+    fname: &'a str,
+    chars: Vec<char>,
+    idx: usize,
+    line: usize,
+    col: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(fname: &'a str, src: &str) -> Self {
+        Self {
+            fname,
+            chars: src.chars().collect(),
+            idx: 0,
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position::new(self.fname, self.line, self.col)
+    }
+
+    fn error(&self) -> Error {
+        Error::SyntaxError(Location::At(self.position()))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.idx).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.idx += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            self.bump();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Error> {
+        self.skip_ws();
+        if self.peek() == Some(expected) {
+            self.bump();
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        self.skip_ws();
+        let mut ident = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || "_-:./+".contains(c) {
+                ident.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        if ident.is_empty() {
+            Err(self.error())
+        } else {
+            Ok(ident)
+        }
+    }
+
+    // 外側の括弧を除いた中身を返す。ネストした{}は大文字保護なので落とす
+    fn braced(&mut self) -> Result<String, Error> {
+        self.expect('{')?;
+        let mut depth = 1;
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some('{') => depth += 1,
+                Some('}') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(value);
+                    }
+                }
+                Some(c) => value.push(c),
+                None => return Err(self.error()),
+            }
+        }
+    }
+
+    fn quoted(&mut self) -> Result<String, Error> {
+        self.expect('"')?;
+        let mut depth = 0;
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                Some('{') => depth += 1,
+                Some('}') => depth -= 1,
+                Some('"') if depth == 0 => return Ok(value),
+                Some(c) => value.push(c),
+                None => return Err(self.error()),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<String, Error> {
+        self.skip_ws();
+        match self.peek() {
+            Some('{') => self.braced(),
+            Some('"') => self.quoted(),
+            _ => self.ident(),
+        }
+    }
+}
+
+fn normalize(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+pub fn parse(fname: &str, src: &str) -> Result<Vec<Entry>, Error> {
+    let mut s = Scanner::new(fname, src);
+    let mut entries = Vec::new();
+    loop {
+        // @までは全てコメント扱い
+        while s.peek().map(|c| c != '@').unwrap_or(false) {
+            s.bump();
+        }
+        if s.peek().is_none() {
+            return Ok(entries);
+        }
+        let start = s.position();
+        s.bump();
+        let kind = s.ident()?.to_lowercase();
+        if kind == "comment" || kind == "preamble" || kind == "string" {
+            s.skip_ws();
+            s.braced()?;
+            continue;
+        }
+        s.expect('{')?;
+        let key = s.ident()?;
+        let mut fields = HashMap::new();
+        loop {
+            s.skip_ws();
+            match s.peek() {
+                Some(',') => {
+                    s.bump();
+                }
+                Some('}') => {
+                    s.bump();
+                    break;
+                }
+                _ => {
+                    let name = s.ident()?.to_lowercase();
+                    s.expect('=')?;
+                    let value = s.value()?;
+                    fields.insert(name, normalize(&value));
+                }
+            }
+        }
+        entries.push(Entry {
+            kind,
+            key,
+            fields,
+            location: Location::Span(start, s.position()),
+        });
+    }
+}
+
+pub fn merge(bibs: &[&Bibliography]) -> Bibliography {
+    let mut merged = HashMap::new();
+    for bib in bibs {
+        merged.extend(bib.iter().map(|(k, v)| (k.to_owned(), v.to_owned())));
+    }
+    merged
+}
+
+impl Entry {
+    pub fn authors(&self) -> Vec<&str> {
+        self.fields
+            .get("author")
+            .or_else(|| self.fields.get("editor"))
+            .map(|authors| authors.split(" and ").map(|a| a.trim()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn year(&self) -> &str {
+        self.fields
+            .get("year")
+            .map(|y| y.as_str())
+            .unwrap_or("n.d.")
+    }
+
+    // "Last, First"形式ならカンマの前、そうでなければ最後の語を姓とする
+    fn family_name(author: &str) -> &str {
+        if author.contains(',') {
+            author.split(',').next().unwrap().trim()
+        } else {
+            author.split_whitespace().last().unwrap_or(author)
+        }
+    }
+
+    // "Last, First"を表示用に"First Last"へ並べ替える
+    pub fn display_name(author: &str) -> String {
+        let mut parts = author.split(',').map(|s| s.trim());
+        match (parts.next(), parts.next()) {
+            (Some(last), Some(first)) => format!("{} {}", first, last),
+            _ => author.to_owned(),
+        }
+    }
+
+    pub fn author_year_label(&self) -> String {
+        let authors = self.authors();
+        let names = match authors.as_slice() {
+            [] => self
+                .fields
+                .get("organization")
+                .or_else(|| self.fields.get("institution"))
+                .map(|s| s.to_owned())
+                .unwrap_or_else(|| self.key.clone()),
+            [a] => Self::family_name(a).to_owned(),
+            [a, b] => format!("{} and {}", Self::family_name(a), Self::family_name(b)),
+            [a, ..] => format!("{} et al.", Self::family_name(a)),
+        };
+        format!("{}, {}", names, self.year())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let src = r#"
+% comment
+@comment{ignored}
+@article{nakano2020,
+  author = {Nakano, Masaki and Kitahara, Itaru},
+  title = "{ILRMA} with
+           user hints",
+  year = 2020,
+}
+@misc{rfc7230, title={Hypertext Transfer Protocol (HTTP/1.1)}, organization={IETF}}
+"#;
+        let entries = parse("refs.bib", src).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].kind, "article");
+        assert_eq!(entries[0].key, "nakano2020");
+        assert_eq!(entries[0].fields["title"], "ILRMA with user hints");
+        assert_eq!(entries[0].fields["year"], "2020");
+        assert_eq!(
+            entries[0].location,
+            Location::Span(
+                Position::new("refs.bib", 4, 1),
+                Position::new("refs.bib", 9, 2)
+            )
+        );
+        assert_eq!(entries[0].author_year_label(), "Nakano and Kitahara, 2020");
+        assert_eq!(entries[1].author_year_label(), "IETF, n.d.");
+        assert_eq!(Entry::display_name("Nakano, Masaki"), "Masaki Nakano");
+        assert_eq!(Entry::display_name("中野 将生"), "中野 将生");
+        assert!(parse("refs.bib", "@article{broken, title={").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use super::Error;
+use serde_derive::Deserialize;
+use std::path::Path;
+
+// サイト直下のconfig.json。無いキーは既定値になる
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    pub highlight: Highlight,
+    pub images: Images,
+    pub og_image: OgImage,
+    pub amp: Amp,
+    pub output: Output,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Highlight {
+    pub theme: String,
+    // prefers-color-scheme: darkの時に使うテーマ
+    pub dark_theme: Option<String>,
+    // index.cssのクラスと衝突しないようにハイライト用のクラスに付ける接頭辞
+    pub class_prefix: String,
+}
+
+impl Default for Highlight {
+    fn default() -> Self {
+        Self {
+            theme: "InspiredGitHub".to_owned(),
+            dark_theme: None,
+            class_prefix: "hl-".to_owned(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Images {
+    // PNGとJPEGをWebPに変換して出力し、\imgからはWebPを参照する
+    pub transcode_webp: bool,
+    // これを超える画像(バイト数、画素数)は警告する
+    pub max_bytes: Option<usize>,
+    pub max_pixels: Option<usize>,
+    // max-bytesを超えるJPEGは品質を下げて再エンコードする
+    pub recompress: bool,
+    pub placeholder: PlaceholderStyle,
+}
+
+// 画像の読み込み中に表示するもの
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlaceholderStyle {
+    // ぼかした縮小画像
+    Blur,
+    // 画像の平均色
+    Color,
+    #[serde(rename = "none")]
+    Disabled,
+}
+
+impl Default for Images {
+    fn default() -> Self {
+        Self {
+            transcode_webp: false,
+            max_bytes: None,
+            max_pixels: None,
+            recompress: false,
+            placeholder: PlaceholderStyle::Blur,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct OgImage {
+    // 記事ごとにタイトルなどを描いたカード画像を生成する
+    pub generate: bool,
+    // サイト内のフォントファイル。グリフが無い文字には埋め込みのフォントを使う
+    pub font: Option<String>,
+}
+
+impl Default for OgImage {
+    fn default() -> Self {
+        Self {
+            generate: true,
+            font: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Amp {
+    // AMPの制約に反するページがあればビルドを失敗させる。falseなら警告のみ
+    pub strict: bool,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Output {
+    pub profile: Profile,
+}
+
+// 出力するページの形式
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    Amp,
+    // loading="lazy"の<img>とリンクしたCSSを使う通常のHTML
+    Html5,
+    // HTML5を正規のページとし、AMP版をfoo.amp.htmlに出力する
+    Both,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self {
+            profile: Profile::Amp,
+        }
+    }
+}
+
+pub const CONFIG_FILE: &str = "config.json";
+
+pub fn parse(path: &Path, src: &[u8]) -> Result<Config, Error> {
+    serde_json::from_slice(src).map_err(|e| Error::ConfigError {
+        path: path.to_owned(),
+        desc: e.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let path = Path::new("config.json");
+        assert_eq!(parse(path, b"{}").unwrap(), Config::default());
+        let config = parse(
+            path,
+            br#"{"article": ".+", "highlight": {"dark-theme": "base16-ocean.dark"}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.highlight.theme, "InspiredGitHub");
+        assert_eq!(
+            config.highlight.dark_theme,
+            Some("base16-ocean.dark".to_owned())
+        );
+        assert!(parse(path, br#"{"highlight": {"theme": 1}}"#).is_err());
+        let config = parse(path, br#"{"images": {"max-bytes": 500000}}"#).unwrap();
+        assert_eq!(config.images.max_bytes, Some(500000));
+        assert!(!config.images.recompress);
+        assert_eq!(config.images.placeholder, PlaceholderStyle::Blur);
+        let config = parse(path, br#"{"images": {"placeholder": "none"}}"#).unwrap();
+        assert_eq!(config.images.placeholder, PlaceholderStyle::Disabled);
+        let config = parse(path, br#"{"og-image": {"font": "res/font.otf"}}"#).unwrap();
+        assert!(config.og_image.generate);
+        assert_eq!(config.og_image.font, Some("res/font.otf".to_owned()));
+        assert!(!config.amp.strict);
+        let config = parse(path, br#"{"amp": {"strict": true}}"#).unwrap();
+        assert!(config.amp.strict);
+        assert_eq!(config.output.profile, Profile::Amp);
+        let config = parse(path, br#"{"output": {"profile": "both"}}"#).unwrap();
+        assert_eq!(config.output.profile, Profile::Both);
+        assert!(parse(path, br#"{"output": {"profile": "xhtml"}}"#).is_err());
+    }
+}
@@ -1,15 +1,63 @@
+use super::amp;
+use super::bib::{Bibliography, Entry};
+use super::config::{Config, PlaceholderStyle, Profile};
+use super::css;
+use super::diff;
+use super::html5;
+use super::images;
+use super::math;
+use super::remote;
 use super::value_utils;
 use super::xml;
 use super::xml::{Html, XMLElem};
 use super::{Cmd, Error, Location, TextElem, TextElemAst, ValueAst};
-use log::warn;
-use std::collections::HashMap;
+use log::{info, warn};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use syntect::html::ClassedHTMLGenerator;
-use syntect::parsing::SyntaxSet;
+use std::rc::Rc;
+use syntect::parsing::{
+    BasicScopeStackOp, ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet,
+};
 
 type EResult<T> = Result<T, Error>;
 
+const SITE_URL: &str = "https://namachan10777.dev/";
+
+// ページ単位で変換中に蓄積する状態
+#[derive(Default)]
+pub struct PageState {
+    footnotes: Vec<Vec<XMLElem>>,
+    citations: Vec<String>,
+    // CMD_MARKERの値から引く、出力を生成したコマンドの位置
+    cmd_locations: Vec<Location>,
+}
+
+// コマンドの出力の根に一時的に付ける属性。AMPの違反を報告する位置を引くのに使う
+const CMD_MARKER: &str = "data-tml-cmd";
+
+// kind, 既定のタイトル, アイコン。色はindex.cssで指定する
+const ADMONITION_KINDS: [(&str, &str, &str); 4] = [
+    ("note", "注記", "ℹ"),
+    ("warning", "警告", "⚠"),
+    ("tip", "ヒント", "✔"),
+    ("update", "更新", "↻"),
+];
+
+// ページ内の図表・数式・コードのリストの番号。解析時に一度だけ振り、変換時はコマンドの位置で引く
+#[derive(Default)]
+pub struct Numbering {
+    pub numbers: HashMap<Location, usize>,
+    // \refで参照するidとその表示
+    pub labels: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CitationStyle {
+    Numeric,
+    AuthorYear,
+}
+
 #[derive(Clone)]
 pub struct Context<'a> {
     pub location: Location,
@@ -19,10 +67,27 @@ pub struct Context<'a> {
     pub titles: &'a HashMap<PathBuf, Vec<(PathBuf, Vec<TextElemAst>)>>,
     pub categories: &'a HashMap<String, Vec<(PathBuf, Vec<TextElemAst>)>>,
     pub ss: &'a SyntaxSet,
+    pub config: &'a Config,
     pub sha256: Option<&'a str>,
     pub path: &'a std::path::Path,
     pub aspects: &'a HashMap<PathBuf, (usize, usize)>,
     pub css: &'a str,
+    pub sidenote: bool,
+    pub bibliography: Option<&'a Bibliography>,
+    pub citation_style: CitationStyle,
+    pub csv_tables: &'a HashMap<PathBuf, Vec<Vec<String>>>,
+    pub snippets: &'a HashMap<PathBuf, String>,
+    pub numbering: Option<&'a Numbering>,
+    // fetch-imagesで記録した外部画像の大きさ
+    pub remote_sizes: &'a remote::Lock,
+    pub placeholders: &'a HashMap<PathBuf, images::Placeholder>,
+    // WebPに変換して出力する画像
+    pub transcoded: &'a HashSet<PathBuf>,
+    // \video, \audioから参照できるサイト内のメディアファイル
+    pub media: &'a HashSet<PathBuf>,
+    // og:imageに使う画像(サイト内のパスかURL)
+    pub og_image: Option<&'a str>,
+    pub page: Rc<RefCell<PageState>>,
 }
 
 impl<'a> Context<'a> {
@@ -34,8 +99,162 @@ impl<'a> Context<'a> {
     }
 }
 
-pub fn root(ctx: Context, cmd: Cmd) -> EResult<Html> {
-    Ok(Html::new("html", process_cmd(ctx, cmd)?))
+// 出力形式によってはAMP版とHTML5版の2つのページになる
+pub fn root(ctx: Context, cmd: Cmd) -> EResult<Vec<(PathBuf, Html)>> {
+    let loc = ctx.location.clone();
+    let config = ctx.config;
+    let path = ctx.path;
+    let page = ctx.page.clone();
+    let mut html = process_cmd(ctx, cmd)?;
+    let locations = std::mem::take(&mut page.borrow_mut().cmd_locations);
+    let mut violations = Vec::new();
+    check_amp(&mut html, &locations, &loc, Some(false), &mut violations);
+    pages(config, path, &loc, html, violations)
+}
+
+// 各要素を一度だけAMPの制約で調べ、その要素を生成したコマンドの位置で記録する。
+// 調べ終えた要素からCMD_MARKERを取り除く。in_foreignがNoneなら印を取り除くだけ
+fn check_amp<'a>(
+    elem: &mut XMLElem,
+    locations: &'a [Location],
+    loc: &'a Location,
+    in_foreign: Option<bool>,
+    violations: &mut Vec<(Location, String)>,
+) {
+    let (name, attrs, inner) = match elem {
+        XMLElem::Single(name, attrs) => (name, attrs, None),
+        XMLElem::WithElem(name, attrs, inner) => (name, attrs, Some(inner)),
+        _ => return,
+    };
+    let mut loc = loc;
+    if let Some(i) = attrs
+        .iter()
+        .position(|attr| matches!(attr, xml::Attr::Pair(name, _) if name == CMD_MARKER))
+    {
+        if let xml::Attr::Pair(_, idx) = attrs.remove(i) {
+            if let Some(cmd_loc) = idx.parse::<usize>().ok().and_then(|i| locations.get(i)) {
+                loc = cmd_loc;
+            }
+        }
+    }
+    let in_foreign = in_foreign.and_then(|in_foreign| {
+        let mut found = Vec::new();
+        let in_foreign = amp::check_node(name, attrs, in_foreign, &mut found);
+        for msg in found {
+            let violation = (loc.clone(), msg);
+            if !violations.contains(&violation) {
+                violations.push(violation);
+            }
+        }
+        in_foreign
+    });
+    for child in inner.into_iter().flatten() {
+        check_amp(child, locations, loc, in_foreign, violations);
+    }
+}
+
+// <style amp-custom>をページで使われている規則だけにする
+fn prune_css(path: &Path, html: XMLElem) -> XMLElem {
+    let usage = css::Usage::collect(&html);
+    let prune = |elem: XMLElem| match elem {
+        XMLElem::WithElem(name, attrs, inner)
+            if name == "style"
+                && attrs
+                    .iter()
+                    .any(|attr| matches!(attr, xml::Attr::Single(a) if a == "amp-custom")) =>
+        {
+            let src = inner
+                .iter()
+                .map(|child| match child {
+                    XMLElem::Text(text) | XMLElem::Raw(text) => text.as_str(),
+                    _ => "",
+                })
+                .collect::<String>();
+            let pruned = css::serialize(&css::prune(&css::parse(&src), &usage));
+            info!(
+                "{:?} inline CSS {} -> {} bytes",
+                path,
+                src.len(),
+                pruned.len()
+            );
+            XMLElem::WithElem(name, attrs, vec![XMLElem::Raw(pruned)])
+        }
+        elem => elem,
+    };
+    match html {
+        XMLElem::WithElem(name, attrs, inner) => XMLElem::WithElem(
+            name,
+            attrs,
+            inner
+                .into_iter()
+                .map(|elem| match elem {
+                    XMLElem::WithElem(name, attrs, head) if name == "head" => {
+                        XMLElem::WithElem(name, attrs, head.into_iter().map(prune).collect())
+                    }
+                    elem => elem,
+                })
+                .collect(),
+        ),
+        html => html,
+    }
+}
+
+// AMPのページからoutput.profileに合わせて出力するページを作る。
+// AMP版を出力しない場合は違反を報告しない。HTML5版は共通のスタイルシートを使うので削らない
+fn pages(
+    config: &Config,
+    path: &Path,
+    loc: &Location,
+    html: XMLElem,
+    mut violations: Vec<(Location, String)>,
+) -> EResult<Vec<(PathBuf, Html)>> {
+    let profile = config.output.profile;
+    let mut pages = Vec::new();
+    if profile != Profile::Amp {
+        let stylesheet = resolve(html5::STYLESHEET, path)?;
+        let amphtml = if profile == Profile::Both {
+            Some(SITE_URL.to_owned() + html5::amp_path(path).to_str().unwrap())
+        } else {
+            None
+        };
+        pages.push((
+            path.to_owned(),
+            Html::new(
+                "html",
+                html5::from_amp(&html, stylesheet.to_str().unwrap(), amphtml.as_deref()),
+            ),
+        ));
+    }
+    if profile != Profile::Html5 {
+        let html = prune_css(path, html);
+        // 生成したページには元のファイルが無いので出力先を示す
+        violations.extend(amp::check_page(&html).into_iter().map(|msg| match loc {
+            Location::Generated => (loc.clone(), format!("{:?}: {}", path, msg)),
+            _ => (loc.clone(), msg),
+        }));
+        report_violations(config, violations)?;
+        let amp_path = if profile == Profile::Both {
+            html5::amp_path(path)
+        } else {
+            path.to_owned()
+        };
+        pages.push((amp_path, Html::new("html", html)));
+    }
+    Ok(pages)
+}
+
+// amp.strictなら変換を失敗させ、そうでなければ警告に留める
+fn report_violations(config: &Config, violations: Vec<(Location, String)>) -> EResult<()> {
+    if violations.is_empty() {
+        return Ok(());
+    }
+    if config.amp.strict {
+        return Err(Error::InvalidAmp { violations });
+    }
+    for (loc, msg) in violations {
+        warn!("{} invalid AMP. {}", loc, msg);
+    }
+    Ok(())
 }
 
 fn process_text_elem(ctx: Context, elem: TextElem) -> EResult<XMLElem> {
@@ -57,7 +276,7 @@ fn resolve_link(
             .ok_or_else(|| Error::InvalidLink {
                 link: target.to_owned(),
                 msg: "Cannot calcurate file name".to_owned(),
-                loc: Location::Generated,
+                loc: Box::new(Location::Generated),
             })?
             .to_path_buf());
     }
@@ -83,12 +302,12 @@ fn resolve(target: &str, from: &std::path::Path) -> Result<std::path::PathBuf, E
     let target = verify_link(target).ok_or_else(|| Error::InvalidLink {
         link: PathBuf::from(target),
         msg: "Cannot calcurate file name".to_owned(),
-        loc: Location::Generated,
+        loc: Box::new(Location::Generated),
     })?;
     resolve_link(&std::path::Path::new(&target), from)
 }
 
-fn is_http_url(link: &str) -> bool {
+pub fn is_http_url(link: &str) -> bool {
     let re = regex::Regex::new(r"^https?://").unwrap();
     re.is_match(link)
 }
@@ -108,13 +327,13 @@ fn verify_link(link: &str) -> Option<String> {
     Some(base.to_owned())
 }
 
-fn absolute(loc: &Location, target: &str, from: &std::path::Path) -> Result<String, Error> {
+pub fn absolute(loc: &Location, target: &str, from: &std::path::Path) -> Result<String, Error> {
     if is_http_url(target) {
         return Ok(target.to_owned());
     }
     let target = verify_link(target).ok_or_else(|| Error::InvalidLink {
         link: PathBuf::from(target),
-        loc: loc.to_owned(),
+        loc: Box::new(loc.to_owned()),
         msg: "invalid link".to_owned(),
     })?;
     let mut target_rev = target.split('/').collect::<Vec<_>>();
@@ -128,7 +347,7 @@ fn absolute(loc: &Location, target: &str, from: &std::path::Path) -> Result<Stri
             from_dir = from_dir.parent().ok_or_else(|| Error::InvalidLink {
                 link: PathBuf::from(target.clone()),
                 msg: "Cannot retrive parent directory".to_owned(),
-                loc: loc.to_owned(),
+                loc: Box::new(loc.to_owned()),
             })?;
         } else if target_rev.is_empty() {
             return Ok(from_dir
@@ -155,6 +374,53 @@ fn absolute(loc: &Location, target: &str, from: &std::path::Path) -> Result<Stri
 #[cfg(test)]
 mod test {
     use super::*;
+
+    // サイトのファイルを与えてpathのページを変換する。画像は中身を持たない8x6の画像にする
+    fn convert_page(files: &[(&str, &str)], path: &str) -> EResult<String> {
+        let mut parsed = crate::Parsed::new();
+        for (name, src) in files {
+            let file = if name.ends_with(".tml") {
+                crate::File::Tml(crate::parser::parse(name, src)?, (*src).to_owned())
+            } else if images::is_raster(Path::new(name)) {
+                crate::File::Image(image::DynamicImage::new_rgb8(8, 6), Vec::new())
+            } else {
+                crate::File::Blob(src.as_bytes().to_vec())
+            };
+            let name = if name.ends_with(".tml") {
+                Path::new(name).with_extension("html")
+            } else {
+                PathBuf::from(name)
+            };
+            parsed.insert(name, file);
+        }
+        let report = crate::analysis::analyze(&parsed)?;
+        let path = Path::new(path);
+        let cmd = match &parsed[path] {
+            crate::File::Tml(cmd, _) => cmd.0.clone(),
+            _ => unreachable!(),
+        };
+        Ok(root(report.get_context(path).unwrap(), cmd)?
+            .into_iter()
+            .map(|(_, html)| html.pretty_print())
+            .collect())
+    }
+
+    fn article(body: &str) -> String {
+        format!(
+            "\\article title={{a}} date=\"2021-01-01\" category=(\"tech\") {{\n{}\n}}",
+            body
+        )
+    }
+
+    fn convert_article(body: &str) -> EResult<String> {
+        convert_page(&[("articles/a.tml", &article(body))], "articles/a.html")
+    }
+
+    // 空白を詰めて、整形による改行やインデントに左右されずに比較する
+    fn squash(html: &str) -> String {
+        html.split_whitespace().collect()
+    }
+
     #[test]
     fn test_resolve_link() {
         let from = Path::new("index.tml");
@@ -218,6 +484,149 @@ mod test {
         assert_eq!(verify_link("../."), None);
     }
 
+    #[test]
+    fn test_extract_snippet() {
+        let src =
+            "fn main() {\n    // ANCHOR: body\n    println!(\"hi\");\n    // ANCHOR_END: body\n}";
+        let text = |lines: Option<&str>, region: Option<&str>| {
+            extract_snippet(src, lines, region).map(|selected| {
+                selected
+                    .into_iter()
+                    .map(|(_, line)| line)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+        };
+        assert_eq!(
+            text(None, None).unwrap(),
+            "fn main() {\n    println!(\"hi\");\n}"
+        );
+        assert_eq!(text(None, Some("body")).unwrap(), "    println!(\"hi\");");
+        assert_eq!(text(Some("1-1"), None).unwrap(), "fn main() {");
+        assert_eq!(text(Some("5"), None).unwrap(), "}");
+        assert_eq!(text(Some("3-"), None).unwrap(), "    println!(\"hi\");\n}");
+        // マーカー行を除いても元のファイルの行番号を保つ
+        assert_eq!(
+            extract_snippet(src, Some("1-5"), None)
+                .unwrap()
+                .into_iter()
+                .map(|(n, _)| n)
+                .collect::<Vec<_>>(),
+            vec![1, 3, 5]
+        );
+        assert_eq!(
+            extract_snippet("a\n\nb\n\n", Some("2-4"), None).unwrap(),
+            vec![(3, "b")]
+        );
+        assert!(text(Some("4-6"), None).is_err());
+        assert!(text(Some("3-2"), None).is_err());
+        assert!(text(None, Some("tail")).is_err());
+        assert!(text(Some("1"), Some("body")).is_err());
+    }
+
+    #[test]
+    fn test_tml_snippet() {
+        let quoted = "\\index title={q} {\n\\p{a}\n% ANCHOR: body\n\\p{b}\n% ANCHOR_END: body\n}";
+        let page = squash(
+            &convert_page(
+                &[
+                    (
+                        "articles/a.tml",
+                        &article("\\blockcode file=\"q.tml\" lines=\"2-6\" linenos=1;"),
+                    ),
+                    ("articles/q.tml", quoted),
+                ],
+                "articles/a.html",
+            )
+            .unwrap(),
+        );
+        // .tmlもソースとして引用でき、行番号は元のファイルのものになる
+        for n in &[2, 4, 6] {
+            let number = format!(r#"<spanclass="line-number"data-nosnippet>{}</span>"#, n);
+            assert!(page.contains(&number), "{} in {}", number, page);
+        }
+        assert!(!page.contains("ANCHOR"));
+    }
+
+    #[test]
+    fn test_parse_line_set() {
+        assert_eq!(parse_line_set("3"), Some(vec![(3, 3)]));
+        assert_eq!(parse_line_set("1, 4-6"), Some(vec![(1, 1), (4, 6)]));
+        assert_eq!(parse_line_set("5-4"), None);
+        assert_eq!(parse_line_set("a"), None);
+        assert_eq!(parse_line_set("1-2-3"), None);
+    }
+
+    #[test]
+    fn test_strip_indent() {
+        assert_eq!(
+            strip_indent("\n    a\n      b\n\n    c\n  "),
+            vec!["a", "  b", "", "c"]
+        );
+        assert_eq!(
+            strip_indent("\t\tif x {\n\t\t\ty\n\t\t}"),
+            vec!["if x {", "\ty", "}"]
+        );
+        assert_eq!(strip_indent("\t a\n\t\tb"), vec![" a", "\tb"]);
+        assert!(strip_indent(" \n\n").is_empty());
+    }
+
+    #[test]
+    fn test_split_diff_lines() {
+        let lines = ["--- a/x", "+++ b/x", "@@ -1,2 +1,2 @@", "---", "+++", " a"];
+        assert_eq!(
+            split_diff_lines(&lines),
+            vec![
+                (" diff-header", "--- a/x", ""),
+                (" diff-header", "+++ b/x", ""),
+                (" diff-hunk", "@@ -1,2 +1,2 @@", ""),
+                (" diff-del", "-", "--"),
+                (" diff-add", "+", "++"),
+                ("", " ", "a"),
+            ]
+        );
+        assert_eq!(
+            split_diff_lines(&["---", "+++"])[0],
+            (" diff-del", "-", "--")
+        );
+    }
+
+    #[test]
+    fn test_blockcode_diff_trailing_context() {
+        let page = convert_article(
+            "\\blockcode lang=\"diff\" before=\"a\nb\nc\n\nd\" after=\"A\nb\nc\n\nd\";",
+        )
+        .unwrap();
+        // 末尾の空行も変更なしの行として残す
+        assert_eq!(page.matches("<span class=\"line").count(), 6, "{}", page);
+        assert!(page.contains("data-nosnippet> </span></span></pre>"));
+    }
+
+    #[test]
+    fn test_media_type() {
+        assert_eq!(media_type(Path::new("a/clip.MP4")), Some("video/mp4"));
+        assert_eq!(media_type(Path::new("song.opus")), Some("audio/ogg"));
+        assert_eq!(media_type(Path::new("a.png")), None);
+        assert_eq!(media_type(Path::new("mp4")), None);
+    }
+
+    #[test]
+    fn test_highlight_lines() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let lines = highlight_lines(&ss, syntax, "hl-", &["/* a", "b */ x<y"]);
+        for line in &lines {
+            assert_eq!(
+                line.matches("<span").count(),
+                line.matches("</span>").count()
+            );
+        }
+        assert!(lines[1].starts_with(
+            "<span class=\"hl-source hl-rust\"><span class=\"hl-comment hl-block hl-rust\">b"
+        ));
+        assert!(lines[1].contains("&lt;"));
+    }
+
     #[test]
     fn test_is_http_url() {
         assert_eq!(is_http_url("http://google.com"), true);
@@ -225,6 +634,325 @@ mod test {
         assert_eq!(is_http_url("ftp://google.com"), false);
         assert_eq!(is_http_url("google.com"), false);
     }
+
+    #[test]
+    fn test_numbering() {
+        let page = convert_page(
+            &[(
+                "articles/a.tml",
+                concat!(
+                    "\\article title={a} date=\"2021-01-01\" category=(\"tech\") {\n",
+                    "  \\p{\\ref id=\"inner\";, \\ref id=\"author\";, \\ref id=\"t\";}\n",
+                    "  \\quote author={\\equation id=\"author\" src=\"a\";} {\n",
+                    "    \\details summary={s} {\\equation id=\"inner\" src=\"b\";}\n",
+                    "  }\n",
+                    "  \\table id=\"t\" [ \\row [ \\cell{1} ] ]\n",
+                    "}\n"
+                ),
+            )],
+            "articles/a.html",
+        )
+        .unwrap();
+        // 属性は本文より前にあるので先に番号が振られ、変換の順序に関わらず\refと一致する
+        assert!(page.contains(r##"<a class="ref" href="#inner">式(2)</a>"##));
+        assert!(page.contains(r##"<a class="ref" href="#author">式(1)</a>"##));
+        assert!(page.contains(r##"<a class="ref" href="#t">表1</a>"##));
+        let inner = page.find(r#"id="inner""#).unwrap();
+        assert!(page[inner..].contains("(2)"));
+        assert!(page.contains(r#"<span class="caption-number">表1</span>"#));
+    }
+
+    #[test]
+    fn test_img_transcoded() {
+        let article = (
+            "articles/a.tml",
+            "\\article title={a} date=\"2021-01-01\" category=(\"tech\") { \\img url=\"../res/a.png\" alt=\"a\"; }",
+        );
+        let config = ("config.json", r#"{"images": {"transcode-webp": true}}"#);
+        let page = convert_page(&[article, ("res/a.png", ""), config], "articles/a.html").unwrap();
+        assert!(page.contains(r#"src="../res/a.webp""#));
+        // 変換しない場合は元の画像を参照する
+        let page = convert_page(&[article, ("res/a.png", "")], "articles/a.html").unwrap();
+        assert!(page.contains(r#"src="../res/a.png""#));
+    }
+
+    #[test]
+    fn test_closest_syntax() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        assert_eq!(closest_syntax(&ss, "rsut").as_deref(), Some("rust"));
+        assert_eq!(closest_syntax(&ss, "pyton").as_deref(), Some("python"));
+        assert_eq!(closest_syntax(&ss, "Rusty").as_deref(), Some("rust"));
+        assert_eq!(closest_syntax(&ss, "nix"), None);
+    }
+
+    #[test]
+    fn test_inline_formatting() {
+        let page = squash(
+            &convert_article(concat!(
+                "\\p{\\em{a}\\strong{b}\\del{c}H\\sub{2}O x\\sup{2} \\kbd{Ctrl}",
+                "\\abbr title=\"HyperText Markup Language\"{HTML}\\ruby rt=\"かん\"{漢}}"
+            ))
+            .unwrap(),
+        );
+        for expected in &[
+            "<em>a</em>",
+            "<strong>b</strong>",
+            "<del>c</del>",
+            "<sub>2</sub>",
+            "<sup>2</sup>",
+            "<kbd>Ctrl</kbd>",
+            r#"<abbrtitle="HyperTextMarkupLanguage">HTML</abbr>"#,
+            "<ruby>漢<rp>(</rp><rt>かん</rt><rp>)</rp></ruby>",
+        ] {
+            assert!(page.contains(expected), "{} in {}", expected, page);
+        }
+        assert!(matches!(
+            convert_article("\\p{\\abbr{HTML}}"),
+            Err(Error::MissingAttribute { .. })
+        ));
+    }
+
+    #[test]
+    fn test_lists() {
+        let page = squash(
+            &convert_article(concat!(
+                "\\ol start=3 style=\"lower-roman\" [ \\n{a} \\ul [ \\n{b} \\task done=1 {c} \\task{d} ] ]\n",
+                "\\dl [ \\dt{t} \\dd{d} ]"
+            ))
+            .unwrap(),
+        );
+        // 直前の項目に続くリストはその項目の中に入れ子にする
+        assert!(page.contains(concat!(
+            r#"<olstart="3"type="i"><liclass="list-elem">a<ul>"#,
+            r#"<liclass="list-elem">b</li>"#,
+            r#"<liclass="list-elemtask-list-item"><inputtype="checkbox"disabledchecked/>c</li>"#,
+            r#"<liclass="list-elemtask-list-item"><inputtype="checkbox"disabled/>d</li>"#,
+            "</ul></li></ol>"
+        )), "{}", page);
+        assert!(page.contains("<dl><dt>t</dt><dd>d</dd></dl>"));
+        // 先頭のリストは項目にする
+        let page = squash(&convert_article("\\ul [ \\ul [ \\n{a} ] ]").unwrap());
+        assert!(page.contains(
+            r#"<ul><liclass="list-elem"><ul><liclass="list-elem">a</li></ul></li></ul>"#
+        ));
+        for (body, desc) in &[
+            (
+                "\\ul {text}",
+                "\\ul can only have commands as child element.",
+            ),
+            (
+                "\\ol {\\n{a} text}",
+                "\\ol can only have commands as child element.",
+            ),
+            (
+                "\\dl [ \\n{a} ]",
+                "\\dl can only have \\dt and \\dd as child element.",
+            ),
+        ] {
+            match convert_article(body) {
+                Err(Error::ProcessError { desc: d, .. }) => assert_eq!(&d, desc),
+                r => panic!("{} must fail: {:?}", body, r.map(|_| ())),
+            }
+        }
+        assert!(matches!(
+            convert_article("\\ol style=\"greek\" [ \\n{a} ]"),
+            Err(Error::InvalidAttribute { .. })
+        ));
+    }
+
+    #[test]
+    fn test_admonition_and_details() {
+        let page = squash(
+            &convert_article(concat!(
+                "\\admonition kind=\"note\" {n}\n",
+                "\\admonition kind=\"update\" title={更新} icon=\"\" {\\p{u}}\n",
+                "\\details summary={開く} open=1 {\\p{中身}}"
+            ))
+            .unwrap(),
+        );
+        assert!(
+            page.contains(concat!(
+                r#"<asideclass="admonitionadmonition-note"><divclass="admonition-title">"#,
+                r#"<spanclass="admonition-icon"aria-hidden="true">ℹ</span>注記</div>"#,
+                r#"<divclass="admonition-body">n</div></aside>"#
+            )),
+            "{}",
+            page
+        );
+        assert!(page.contains(r#"<divclass="admonition-title">更新</div>"#));
+        assert!(page.contains(concat!(
+            r#"<amp-accordionclass="details"disable-session-states><sectionexpanded>"#,
+            r#"<headerclass="details-summary">開く</header>"#
+        )));
+        assert!(page
+            .contains(r#"<script async custom-element="amp-accordion""#.replace(' ', "").as_str()));
+        match convert_article("\\admonition kind=\"danger\" {x}") {
+            Err(Error::InvalidAttribute { reason, .. }) => {
+                assert_eq!(reason, "kind must be one of note, warning, tip, update")
+            }
+            _ => panic!("unknown kind must fail"),
+        }
+        assert!(matches!(
+            convert_article("\\details {x}"),
+            Err(Error::MissingAttribute { .. })
+        ));
+    }
+
+    #[test]
+    fn test_custom_css_unescaped() {
+        let page = convert_page(
+            &[
+                ("index.css", "body>p{margin:0}"),
+                ("index.tml", "\\index title={t} { \\p{a} }"),
+            ],
+            "index.html",
+        )
+        .unwrap();
+        assert!(page.contains("body>p{margin:0}"), "{}", page);
+    }
+
+    #[test]
+    fn test_quote() {
+        let page = squash(
+            &convert_article(concat!(
+                "\\quote cite=\"https://example.com/a\" author={誰か} source={本} {\\p{引用}}\n",
+                "\\quote {\\p{出典なし}}\n",
+                "\\p{\\q cite=\"https://example.com/b\" {短い}}"
+            ))
+            .unwrap(),
+        );
+        assert!(page.contains(concat!(
+            r#"<figureclass="quote"data-nosnippet><blockquotecite="https://example.com/a"><p>引用</p></blockquote>"#,
+            r#"<figcaptionclass="quote-attribution">—誰か,<cite><ahref="https://example.com/a">本</a></cite></figcaption></figure>"#
+        )), "{}", page);
+        assert!(page.contains(r#"<figureclass="quote"data-nosnippet><blockquote><p>出典なし</p></blockquote></figure>"#));
+        assert!(page.contains(r#"<qcite="https://example.com/b"data-nosnippet>短い</q>"#));
+        assert!(convert_article("\\quote cite=\"../../../x.html\" {x}").is_err());
+    }
+
+    fn gallery_files(body: &str) -> EResult<String> {
+        let article = article(body);
+        convert_page(
+            &[
+                ("articles/a.tml", &article),
+                ("res/a.png", ""),
+                ("res/b.png", ""),
+            ],
+            "articles/a.html",
+        )
+    }
+
+    #[test]
+    fn test_gallery() {
+        let page = squash(
+            &gallery_files(concat!(
+                "\\gallery caption={c} columns=2 [\n",
+                "  \\img url=\"../res/a.png\" alt=\"a\" caption={ca};\n",
+                "  \\img url=\"../res/b.png\" alt=\"b\";\n",
+                "]"
+            ))
+            .unwrap(),
+        );
+        assert!(page.contains(r#"<figureclass="gallery"><divclass="gallery-grid"style="grid-template-columns:repeat(2,1fr)">"#), "{}", page);
+        assert!(page.contains(r#"<figcaptionclass="gallery-item-caption">ca</figcaption>"#));
+        assert!(page.contains(r#"<spanclass="caption-number">図1</span>c"#));
+        for body in &[
+            "\\gallery columns=0 [ \\img url=\"../res/a.png\" alt=\"a\"; ]",
+            "\\gallery [ \\p{a} ]",
+        ] {
+            assert!(gallery_files(body).is_err(), "{} must fail", body);
+        }
+    }
+
+    #[test]
+    fn test_gallery_lightbox_script() {
+        let script = r#"src="https://cdn.ampproject.org/v0/amp-lightbox-gallery-0.1.js""#;
+        let page = gallery_files("\\gallery caption={c} [ \\img url=\"../res/a.png\" alt=\"a\"; ]")
+            .unwrap();
+        let head = &page[..page.find("</head>").unwrap()];
+        assert!(head.contains(script), "{}", head);
+        assert!(page.contains("lightbox"));
+        // lightboxを使わないページはスクリプトを読み込まない
+        assert!(!convert_article("\\p{a}").unwrap().contains(script));
+    }
+
+    #[test]
+    fn test_footnote_in_titles() {
+        let a = "\\article title={a\\footnote{titlenote}} date=\"2021-01-01\" category=(\"tech\") {\n\\p{x\\footnote{body}}\n}";
+        let b = "\\article title={b\\em{\\footnote{titlenote}}} date=\"2021-01-02\" category=(\"tech\") {\n\\p{y}\n}";
+        let page = convert_page(
+            &[("articles/a.tml", a), ("articles/b.tml", b)],
+            "articles/a.html",
+        )
+        .unwrap();
+        // タイトルの注釈は登録せず、本文の注釈が1番になる
+        assert!(page.contains("prev-article"), "{}", page);
+        assert!(!page.contains("titlenote"), "{}", page);
+        assert_eq!(page.matches("class=\"footnote-ref\"").count(), 1);
+        assert!(page.contains("id=\"fnref-1\""));
+        assert!(!page.contains("fnref-2"));
+    }
+
+    #[test]
+    fn test_check_amp() {
+        let marker = |i: usize| xml::Attr::Pair(CMD_MARKER.to_owned(), i.to_string());
+        let outer = Location::At(crate::Position::new("a.tml", 1, 1));
+        let inner = Location::At(crate::Position::new("a.tml", 2, 3));
+        let img = XMLElem::Single("img".to_owned(), vec![marker(1)]);
+        let mut html = XMLElem::WithElem(
+            "div".to_owned(),
+            vec![marker(0)],
+            vec![
+                img.clone(),
+                img,
+                XMLElem::Single("img".to_owned(), vec![]),
+                XMLElem::WithElem("math".to_owned(), vec![], vec![]),
+            ],
+        );
+        let mut violations = Vec::new();
+        check_amp(
+            &mut html,
+            &[outer.clone(), inner.clone()],
+            &Location::Generated,
+            Some(false),
+            &mut violations,
+        );
+        let img = "<img> is not allowed. use amp-img".to_owned();
+        // 同じ位置の同じ違反は1つにまとめ、印の無い要素は親のコマンドの位置で報告する
+        assert_eq!(
+            violations,
+            vec![
+                (inner, img.clone()),
+                (outer.clone(), img),
+                (
+                    outer,
+                    "<math> is not allowed. MathML is not supported. use \\math or \\equation"
+                        .to_owned()
+                ),
+            ]
+        );
+        assert!(!format!("{}", html).contains(CMD_MARKER));
+    }
+}
+
+fn component_scripts(body: &[XMLElem]) -> Vec<XMLElem> {
+    let mut components = BTreeSet::new();
+    amp::used_components(body, &mut components);
+    components
+        .into_iter()
+        .map(|name| {
+            let src = format!("https://cdn.ampproject.org/v0/{}-0.1.js", name);
+            XMLElem::WithElem(
+                "script".to_owned(),
+                vec![
+                    xml::Attr::Single("async".to_owned()),
+                    xml::Attr::Pair("custom-element".to_owned(), name),
+                    xml::Attr::Pair("src".to_owned(), src),
+                ],
+                Vec::new(),
+            )
+        })
+        .collect()
 }
 
 fn execute_index(
@@ -232,11 +960,7 @@ fn execute_index(
     attrs: HashMap<String, ValueAst>,
     inner: Vec<TextElemAst>,
 ) -> EResult<XMLElem> {
-    let title = value_utils::get_text(&attrs, "title", &ctx.location)?;
-    let title = title
-        .iter()
-        .map(|(e, loc)| process_text_elem(ctx.fork_with_loc(loc.to_owned()), e.to_owned()))
-        .collect::<EResult<Vec<_>>>()?;
+    let title = process_title(&ctx, value_utils::get_text(&attrs, "title", &ctx.location)?)?;
     let mut body = vec![xml!(header [] [xml!(h1 [style="font-weight: 500"] title.clone())])];
     body.append(
         &mut inner
@@ -244,6 +968,12 @@ fn execute_index(
             .map(|(e, loc)| process_text_elem(ctx.fork_with_loc(loc), e))
             .collect::<EResult<Vec<_>>>()?,
     );
+    if let Some(references) = references_section(&ctx) {
+        body.push(references);
+    }
+    if let Some(footnotes) = footnotes_section(&ctx) {
+        body.push(footnotes);
+    }
     body.push(const_footer());
     let mut header = gen_headers(
         &ctx.path,
@@ -251,6 +981,7 @@ fn execute_index(
         title,
         ctx.path.to_str().unwrap(),
         ctx.css,
+        ctx.og_image,
     )?;
     header.push(xml!(meta [property="og:type", content="profile"]));
     Ok(html(body, header))
@@ -262,8 +993,21 @@ fn gen_headers(
     title_xml: Vec<XMLElem>,
     page_name: &str,
     css: &str,
+    og_image: Option<&str>,
 ) -> Result<Vec<XMLElem>, Error> {
     let url = "https://namachan10777.dev/".to_owned() + path.to_str().unwrap();
+    // カード画像が無いページはサイトのアイコンを小さく表示する
+    let (image_url, card) = match og_image {
+        Some(image) if is_http_url(image) => (image.to_owned(), "summary_large_image"),
+        Some(image) => (
+            "https://namachan10777.dev/".to_owned() + image,
+            "summary_large_image",
+        ),
+        None => (
+            "https://namachan10777.dev/res/icon.webp".to_owned(),
+            "summary",
+        ),
+    };
     let amp_boilerplate = concat!(
         "body{",
         "-webkit-animation:-amp-start 8s steps(1,end) 0s 1 normal both;",
@@ -297,15 +1041,15 @@ fn gen_headers(
         XMLElem::WithElem(
             "style".to_owned(),
             vec![xml::Attr::Single("amp-custom".to_owned())],
-            vec![XMLElem::Text(css.to_owned())],
+            vec![XMLElem::Raw(css.to_owned())],
         ),
-        xml!(meta [name="twitter:image:src", content="https://namachan10777.dev/res/icon.webp"]),
+        xml!(meta [name="twitter:image:src", content=&image_url]),
         xml!(meta [name="twitter:site", content="@namachan10777"]),
-        xml!(meta [name="twitter:card", content="summary"]),
+        xml!(meta [name="twitter:card", content=card]),
         xml!(meta [name="twitter:creator", content="@namachan10777"]),
         xml!(meta [property="og:url", content=&url]),
         xml!(meta [property="og:site_name", content="namachan10777"]),
-        xml!(meta [property="og:image", content="https://namachan10777.dev/res/icon.webp"]),
+        xml!(meta [property="og:image", content=&image_url]),
         xml!(meta [name="viewport", content="width=device-width,initial-scale=1"]),
         XMLElem::WithElem(
             "style".to_string(),
@@ -352,7 +1096,7 @@ fn gen_headers(
         body_str
     };
     let body_str = body_str.trim().to_owned() + "……";
-    header.push(xml!(title [] title_xml));
+    header.push(xml!(title [] [xml!(title_str.clone())]));
     header.push(xml!(meta [property="og:title", content=&title_str]));
     header.push(xml!(meta [name="twitter:title", content=&title_str]));
     header.push(xml!(meta [property="og:description", content=body_str]));
@@ -361,7 +1105,9 @@ fn gen_headers(
     Ok(header)
 }
 
-fn html(body: Vec<XMLElem>, header: Vec<XMLElem>) -> XMLElem {
+// 本文で使われたAMPコンポーネントのスクリプトはここでまとめて読み込む
+fn html(body: Vec<XMLElem>, mut header: Vec<XMLElem>) -> XMLElem {
+    header.append(&mut component_scripts(&body));
     XMLElem::WithElem(
         "html".to_owned(),
         vec![
@@ -390,11 +1136,34 @@ fn execute_article(
     inner: Vec<TextElemAst>,
 ) -> EResult<XMLElem> {
     let hashed_short = &ctx.sha256.unwrap()[..7];
-    let title = value_utils::get_text(&attrs, "title", &ctx.location)?;
-    let title_xml = title
-        .iter()
-        .map(|(e, loc)| process_text_elem(ctx.fork_with_loc(loc.to_owned()), e.to_owned()))
-        .collect::<EResult<Vec<_>>>()?;
+    let sidenote = match value_utils::verify_str(&attrs, "footnote-style", &ctx.location)? {
+        None | Some("foot") => false,
+        Some("side") => true,
+        Some(_) => {
+            return Err(Error::InvalidAttribute {
+                name: "footnote-style".to_owned(),
+                reason: "footnote-style must be \"foot\" or \"side\"".to_owned(),
+                loc: Box::new(ctx.location),
+            })
+        }
+    };
+    let citation_style = match value_utils::verify_str(&attrs, "citation-style", &ctx.location)? {
+        None | Some("numeric") => CitationStyle::Numeric,
+        Some("author-year") => CitationStyle::AuthorYear,
+        Some(_) => {
+            return Err(Error::InvalidAttribute {
+                name: "citation-style".to_owned(),
+                reason: "citation-style must be \"numeric\" or \"author-year\"".to_owned(),
+                loc: Box::new(ctx.location),
+            })
+        }
+    };
+    let ctx = Context {
+        sidenote,
+        citation_style,
+        ..ctx
+    };
+    let title_xml = process_title(&ctx, value_utils::get_text(&attrs, "title", &ctx.location)?)?;
     let index_path = resolve("index.html", ctx.path)?;
     let category =
         value_utils::get_list(&attrs, "category", &ctx.location, &crate::ValueType::Str)?
@@ -418,18 +1187,14 @@ fn execute_article(
         let href_path = resolve_link(prev_path, ctx.path)?;
         footer_inner.push(xml!(a
             [href=href_path.to_str().unwrap(), class="prev-article"]
-            prev_title
-                .iter()
-                .map(|(e, loc)| process_text_elem(ctx.fork_with_loc(loc.to_owned()), e.clone())).collect::<EResult<Vec<_>>>()?
+            process_title(&ctx, prev_title)?
         ));
     }
     if let Some((next_path, next_title)) = ctx.next {
         let href_path = resolve_link(next_path, ctx.path)?;
         footer_inner.push(xml!(a
             [href=href_path.to_str().unwrap(), class="next-article"]
-            next_title
-                .iter()
-                .map(|(e, loc)| process_text_elem(ctx.fork_with_loc(loc.to_owned()), e.clone())).collect::<EResult<Vec<_>>>()?
+            process_title(&ctx, next_title)?
         ));
     }
     let mut body_xml = inner
@@ -442,9 +1207,16 @@ fn execute_article(
         title_xml,
         ctx.path.to_str().unwrap(),
         ctx.css,
+        ctx.og_image,
     )?;
     header.push(xml!(meta [name="og:type", content="article"]));
     body.append(&mut body_xml);
+    if let Some(references) = references_section(&ctx) {
+        body.push(references);
+    }
+    if let Some(footnotes) = footnotes_section(&ctx) {
+        body.push(footnotes);
+    }
     body.push(xml!(div [] footer_inner));
     body.push(const_footer());
 
@@ -497,6 +1269,77 @@ fn constraint_img_size(size: (usize, usize), w: usize) -> (f64, f64) {
     (w as f64, (w as f64 * (size.1 as f64 / size.0 as f64)))
 }
 
+// 変換されたWebPがあればそちらを参照する
+fn img_src(ctx: &Context, url: &str) -> EResult<String> {
+    if is_http_url(url) {
+        return Ok(url.to_owned());
+    }
+    let local_path = PathBuf::from(absolute(&ctx.location, url, ctx.path)?);
+    Ok(images::transcoded_path(Path::new(url))
+        .filter(|_| ctx.transcoded.contains(&local_path))
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|| url.to_owned()))
+}
+
+// 縮小版と元画像(srcはimg_srcで変換後のもの)を並べたsrcset。縮小版を持たない画像は空になる
+fn img_srcset(url: &str, src: &str, width: usize, thumbnail: bool) -> Vec<String> {
+    if is_http_url(url) || !images::has_variants(Path::new(url)) {
+        return Vec::new();
+    }
+    let mut widths = images::variant_widths(width);
+    if thumbnail && width > images::THUMBNAIL_WIDTH {
+        widths.insert(0, images::THUMBNAIL_WIDTH);
+    }
+    if widths.is_empty() {
+        return Vec::new();
+    }
+    let mut srcset = widths
+        .into_iter()
+        .map(|width| {
+            format!(
+                "{} {}w",
+                images::variant_path(Path::new(url), width).to_string_lossy(),
+                width
+            )
+        })
+        .collect::<Vec<_>>();
+    srcset.push(format!("{} {}w", src, width));
+    srcset
+}
+
+// 読み込みが終わるまでAMPが表示するプレースホルダ
+fn img_placeholder(ctx: &Context, local_path: &Path) -> Option<XMLElem> {
+    ctx.placeholders.get(local_path).map(|placeholder| {
+        let mut attrs = vec![xml::Attr::Single("placeholder".to_owned())];
+        match ctx.config.images.placeholder {
+            PlaceholderStyle::Color => {
+                attrs.push(xml::Attr::Pair(
+                    "class".to_owned(),
+                    "img-placeholder".to_owned(),
+                ));
+                attrs.push(xml::Attr::Pair(
+                    "style".to_owned(),
+                    format!("background-color: {}", placeholder.color),
+                ));
+                XMLElem::WithElem("div".to_owned(), attrs, vec![])
+            }
+            _ => {
+                attrs.push(xml::Attr::Pair(
+                    "class".to_owned(),
+                    "img-placeholder img-placeholder-blur".to_owned(),
+                ));
+                attrs.push(xml::Attr::Pair(
+                    "src".to_owned(),
+                    placeholder.data_uri.to_owned(),
+                ));
+                attrs.push(xml::Attr::Pair("alt".to_owned(), "".to_owned()));
+                attrs.push(xml::Attr::Pair("layout".to_owned(), "fill".to_owned()));
+                XMLElem::WithElem("amp-img".to_owned(), attrs, vec![])
+            }
+        }
+    })
+}
+
 fn execute_img(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
     let url = value_utils::get_str(&attrs, "url", &ctx.location)?;
     let alt = value_utils::get_str(&attrs, "alt", &ctx.location)?;
@@ -504,55 +1347,81 @@ fn execute_img(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLEle
     let img_classes = value_utils::verify_str(&attrs, "img-class", &ctx.location)?;
     let w = value_utils::verify_int(&attrs, "w", &ctx.location)?;
     let h = value_utils::verify_int(&attrs, "h", &ctx.location)?;
+    let local_path = if is_http_url(url) {
+        PathBuf::new()
+    } else {
+        PathBuf::from(absolute(&ctx.location, url, ctx.path)?)
+    };
     let raw_size = if is_http_url(url) {
-        // TODO: try get image with reqwest
-        (100, 100)
+        match (w, h, ctx.remote_sizes.get(url)) {
+            (_, _, Some(size)) => (size.width, size.height),
+            // 両方指定されていれば縦横比は分かるので取得していなくてもよい
+            (Some(w), Some(h), None) => (w as usize, h as usize),
+            _ => {
+                return Err(Error::InvalidLink {
+                    msg: format!(
+                        "size of remote image is not recorded in {}. run `engine fetch-images` or specify both w and h",
+                        remote::LOCKFILE
+                    ),
+                    loc: Box::new(ctx.location.clone()),
+                    link: PathBuf::from(url),
+                })
+            }
+        }
     } else {
         ctx.aspects
-            .get(Path::new(&absolute(&ctx.location, url, ctx.path)?))
+            .get(&local_path)
             .copied()
             .ok_or_else(|| Error::InvalidLink {
                 msg: "local image not found".to_owned(),
-                loc: ctx.location.clone(),
+                loc: Box::new(ctx.location.clone()),
                 link: PathBuf::from(url),
             })?
     };
+    let src = img_src(&ctx, url)?;
+    let srcset = img_srcset(url, &src, raw_size.0, false);
+    let url = src.as_str();
     let aspect = constraint_img_size(raw_size, 70);
     let width_spec = w
         .map(|w| format!("max-width: {}em", w))
         .unwrap_or_else(|| "".to_owned());
-    let w = w.map(|n| n as f64).unwrap_or(aspect.0);
-    let h = h.map(|n| n as f64).unwrap_or(aspect.1);
+    // 表示幅が分かっていればそれ以上の解像度は選ばせない
+    let sizes = w
+        .map(|w| format!("(max-width: {w}em) 100vw, {w}em", w = w))
+        .unwrap_or_else(|| "100vw".to_owned());
+    // 片方だけ指定された場合はもう片方を縦横比から決める
+    let (w, h) = match (w, h) {
+        (Some(w), Some(h)) => (w as f64, h as f64),
+        (Some(w), None) => constraint_img_size(raw_size, w as usize),
+        (None, Some(h)) => {
+            let (h, w) = constraint_img_size((raw_size.1, raw_size.0), h as usize);
+            (w, h)
+        }
+        (None, None) => aspect,
+    };
     let img_class = img_classes.unwrap_or("");
-    // FIXME determine width and height by reading actual image.
+    let mut img_attrs = vec![
+        xml::Attr::Pair("class".to_owned(), img_class.to_owned()),
+        xml::Attr::Pair("src".to_owned(), url.to_owned()),
+        xml::Attr::Pair("alt".to_owned(), alt.to_owned()),
+        xml::Attr::Pair("width".to_owned(), format!("{}", w)),
+        xml::Attr::Pair("height".to_owned(), format!("{}", h)),
+        xml::Attr::Pair("layout".to_owned(), "responsive".to_owned()),
+    ];
+    if !srcset.is_empty() {
+        img_attrs.push(xml::Attr::Pair("srcset".to_owned(), srcset.join(", ")));
+        img_attrs.push(xml::Attr::Pair("sizes".to_owned(), sizes));
+    }
+    let placeholder = img_placeholder(&ctx, &local_path);
+    let img = XMLElem::WithElem(
+        "amp-img".to_owned(),
+        img_attrs,
+        placeholder.into_iter().collect(),
+    );
     if let Some(classes) = classes {
-        Ok(
-            xml!(div [class=(classes.to_owned() + " amp-img-container"), style=width_spec] [XMLElem::Single(
-                "amp-img".to_owned(),
-                vec![
-                    xml::Attr::Pair("class".to_owned(), img_class.to_owned()),
-                    xml::Attr::Pair("src".to_owned(), url.to_owned()),
-                    xml::Attr::Pair("alt".to_owned(), alt.to_owned()),
-                    xml::Attr::Pair("width".to_owned(), format!("{}", w)),
-                    xml::Attr::Pair("height".to_owned(), format!("{}", h)),
-                    xml::Attr::Pair("layout".to_owned(), "responsive".to_owned()),
-                ],
-            )]),
-        )
+        Ok(xml!(div [class=(classes.to_owned() + " amp-img-container"), style=width_spec] [img]))
     } else {
-        Ok(
-            xml!(div [class="amp-img-container", style=width_spec] [XMLElem::Single(
-                "amp-img".to_owned(),
-                vec![
-                    xml::Attr::Pair("class".to_owned(), img_class.to_owned()),
-                    xml::Attr::Pair("src".to_owned(), url.to_owned()),
-                    xml::Attr::Pair("alt".to_owned(), alt.to_owned()),
-                    xml::Attr::Pair("width".to_owned(), format!("{}", w)),
-                    xml::Attr::Pair("height".to_owned(), format!("{}", h)),
-                    xml::Attr::Pair("layout".to_owned(), "responsive".to_owned()),
-                ],
-            )]),
-        )
+        Ok(xml!(div [class="amp-img-container", style=width_spec] [img]))
     }
 }
 
@@ -595,30 +1464,287 @@ fn execute_center(
     Ok(xml!(div [class=class] inner))
 }
 
-fn execute_ul(ctx: Context, inner: Vec<TextElemAst>) -> EResult<XMLElem> {
-    let inner = inner
-        .into_iter()
-        .map(|(e, loc)| match e {
-            TextElem::Cmd(cmd) => match cmd.name.as_str() {
-                "n" => {
-                    let inner = cmd
-                        .inner
-                        .into_iter()
-                        .map(|(e, loc)| process_text_elem(ctx.fork_with_loc(loc), e))
-                        .collect::<EResult<Vec<_>>>()?;
-                    Ok(xml!(li [class="list-elem"] inner))
+// 子要素を<li>に包む。直前の項目に続く\ul/\olはその項目の入れ子にする
+fn process_list_items(
+    ctx: Context,
+    list_name: &str,
+    inner: Vec<TextElemAst>,
+) -> EResult<Vec<XMLElem>> {
+    let mut items: Vec<XMLElem> = Vec::new();
+    for (e, loc) in inner {
+        let cmd = match e {
+            TextElem::Plain(s) if s.trim().is_empty() => continue,
+            TextElem::Cmd(cmd) => cmd,
+            _ => {
+                return Err(Error::ProcessError {
+                    loc,
+                    desc: format!("\\{} can only have commands as child element.", list_name),
+                })
+            }
+        };
+        match cmd.name.as_str() {
+            "n" => {
+                let inner = process_text(ctx.fork_with_loc(loc), cmd.inner)?;
+                items.push(xml!(li [class="list-elem"] inner));
+            }
+            "task" => {
+                let done = value_utils::verify_int(&cmd.attrs, "done", &loc)?.unwrap_or(0) != 0;
+                let mut checkbox_attrs = vec![
+                    xml::Attr::Pair("type".to_owned(), "checkbox".to_owned()),
+                    xml::Attr::Single("disabled".to_owned()),
+                ];
+                if done {
+                    checkbox_attrs.push(xml::Attr::Single("checked".to_owned()));
                 }
-                _ => Ok(xml!(
-                    li[class = "list-elem"][process_cmd(ctx.fork_with_loc(loc), cmd)?]
-                )),
-            },
-            _ => unreachable!(),
-        })
-        .collect::<EResult<Vec<_>>>()?;
-    Ok(xml!(ul [] inner))
+                let mut inner = vec![XMLElem::Single("input".to_owned(), checkbox_attrs)];
+                inner.append(&mut process_text(ctx.fork_with_loc(loc), cmd.inner)?);
+                items.push(xml!(li [class="list-elem task-list-item"] inner));
+            }
+            "ul" | "ol" if !items.is_empty() => {
+                let nested = process_cmd(ctx.fork_with_loc(loc), cmd)?;
+                if let Some(XMLElem::WithElem(_, _, children)) = items.last_mut() {
+                    children.push(nested);
+                }
+            }
+            _ => items.push(xml!(
+                li[class = "list-elem"][process_cmd(ctx.fork_with_loc(loc), cmd)?]
+            )),
+        }
+    }
+    Ok(items)
 }
 
-fn execute_link(
+fn execute_ul(ctx: Context, inner: Vec<TextElemAst>) -> EResult<XMLElem> {
+    Ok(xml!(ul [] process_list_items(ctx, "ul", inner)?))
+}
+
+fn execute_ol(
+    ctx: Context,
+    attrs: HashMap<String, ValueAst>,
+    inner: Vec<TextElemAst>,
+) -> EResult<XMLElem> {
+    let mut ol_attrs = Vec::new();
+    if let Some(start) = value_utils::verify_int(&attrs, "start", &ctx.location)? {
+        ol_attrs.push(xml::Attr::Pair("start".to_owned(), start.to_string()));
+    }
+    if let Some(style) = value_utils::verify_str(&attrs, "style", &ctx.location)? {
+        let typ = match style {
+            "decimal" => "1",
+            "lower-alpha" => "a",
+            "upper-alpha" => "A",
+            "lower-roman" => "i",
+            "upper-roman" => "I",
+            _ => {
+                return Err(Error::InvalidAttribute {
+                    name: "style".to_owned(),
+                    reason: "style must be one of decimal, lower-alpha, upper-alpha, lower-roman and upper-roman".to_owned(),
+                    loc: Box::new(ctx.location),
+                })
+            }
+        };
+        ol_attrs.push(xml::Attr::Pair("type".to_owned(), typ.to_owned()));
+    }
+    Ok(XMLElem::WithElem(
+        "ol".to_owned(),
+        ol_attrs,
+        process_list_items(ctx, "ol", inner)?,
+    ))
+}
+
+fn execute_dl(ctx: Context, inner: Vec<TextElemAst>) -> EResult<XMLElem> {
+    let mut items = Vec::new();
+    for (e, loc) in inner {
+        match e {
+            TextElem::Plain(s) if s.trim().is_empty() => (),
+            TextElem::Cmd(cmd) if cmd.name == "dt" || cmd.name == "dd" => {
+                items.push(XMLElem::WithElem(
+                    cmd.name.clone(),
+                    Vec::new(),
+                    process_text(ctx.fork_with_loc(loc), cmd.inner)?,
+                ));
+            }
+            _ => {
+                return Err(Error::ProcessError {
+                    loc,
+                    desc: "\\dl can only have \\dt and \\dd as child element.".to_owned(),
+                })
+            }
+        }
+    }
+    Ok(xml!(dl [] items))
+}
+
+fn execute_admonition(
+    ctx: Context,
+    attrs: HashMap<String, ValueAst>,
+    inner: Vec<TextElemAst>,
+) -> EResult<XMLElem> {
+    let kind = value_utils::get_str(&attrs, "kind", &ctx.location)?;
+    let (kind, default_title, default_icon) = ADMONITION_KINDS
+        .iter()
+        .find(|(name, _, _)| *name == kind)
+        .ok_or_else(|| Error::InvalidAttribute {
+            name: "kind".to_owned(),
+            reason: format!(
+                "kind must be one of {}",
+                ADMONITION_KINDS
+                    .iter()
+                    .map(|(name, _, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            loc: Box::new(ctx.location.clone()),
+        })?;
+    let mut title = Vec::new();
+    let icon = value_utils::verify_str(&attrs, "icon", &ctx.location)?.unwrap_or(default_icon);
+    // icon=""でアイコンを消せる
+    if !icon.is_empty() {
+        title.push(XMLElem::WithElem(
+            "span".to_owned(),
+            vec![
+                xml::Attr::Pair("class".to_owned(), "admonition-icon".to_owned()),
+                xml::Attr::Pair("aria-hidden".to_owned(), "true".to_owned()),
+            ],
+            vec![XMLElem::Text(icon.to_owned())],
+        ));
+    }
+    match value_utils::verify_text(&attrs, "title", &ctx.location)? {
+        Some(title_text) => title.append(&mut process_text(ctx.clone(), title_text.to_vec())?),
+        None => title.push(XMLElem::Text((*default_title).to_owned())),
+    }
+    let body = process_text(ctx, inner)?;
+    Ok(
+        xml!(aside [class=format!("admonition admonition-{}", kind)] [
+            xml!(div [class="admonition-title"] title),
+            xml!(div [class="admonition-body"] body)
+        ]),
+    )
+}
+
+fn execute_details(
+    ctx: Context,
+    attrs: HashMap<String, ValueAst>,
+    inner: Vec<TextElemAst>,
+) -> EResult<XMLElem> {
+    let summary = value_utils::get_text(&attrs, "summary", &ctx.location)?;
+    let open = value_utils::verify_int(&attrs, "open", &ctx.location)?.unwrap_or(0) != 0;
+    let summary = process_text(ctx.clone(), summary.to_vec())?;
+    let body = process_text(ctx.clone(), inner)?;
+    let mut section_attrs = Vec::new();
+    if open {
+        section_attrs.push(xml::Attr::Single("expanded".to_owned()));
+    }
+    Ok(XMLElem::WithElem(
+        "amp-accordion".to_owned(),
+        vec![
+            xml::Attr::Pair("class".to_owned(), "details".to_owned()),
+            xml::Attr::Single("disable-session-states".to_owned()),
+        ],
+        vec![XMLElem::WithElem(
+            "section".to_owned(),
+            section_attrs,
+            vec![
+                xml!(header [class="details-summary"] summary),
+                xml!(div [class="details-body"] body),
+            ],
+        )],
+    ))
+}
+
+// 引用元のURLは外部URLかサイト内の正しいリンクでなければならない
+fn verify_cite_url(ctx: &Context, attrs: &HashMap<String, ValueAst>) -> EResult<Option<String>> {
+    match value_utils::verify_str(attrs, "cite", &ctx.location)? {
+        Some(url) => {
+            absolute(&ctx.location, url, ctx.path)?;
+            Ok(Some(url.to_owned()))
+        }
+        None => Ok(None),
+    }
+}
+
+fn execute_quote(
+    ctx: Context,
+    attrs: HashMap<String, ValueAst>,
+    inner: Vec<TextElemAst>,
+) -> EResult<XMLElem> {
+    let cite = verify_cite_url(&ctx, &attrs)?;
+    let author = value_utils::verify_text(&attrs, "author", &ctx.location)?;
+    let source = value_utils::verify_text(&attrs, "source", &ctx.location)?;
+    let mut blockquote_attrs = Vec::new();
+    if let Some(cite) = &cite {
+        blockquote_attrs.push(xml::Attr::Pair("cite".to_owned(), cite.to_owned()));
+    }
+    let mut figure = vec![XMLElem::WithElem(
+        "blockquote".to_owned(),
+        blockquote_attrs,
+        process_text(ctx.clone(), inner)?,
+    )];
+    let mut attribution = Vec::new();
+    if let Some(author) = author {
+        attribution.append(&mut process_text(ctx.clone(), author.to_vec())?);
+    }
+    if let Some(source) = source {
+        if !attribution.is_empty() {
+            attribution.push(XMLElem::Text(", ".to_owned()));
+        }
+        let source = process_text(ctx.clone(), source.to_vec())?;
+        let source = match &cite {
+            Some(cite) => vec![xml!(a [href=cite] source)],
+            None => source,
+        };
+        attribution.push(xml!(cite [] source));
+    }
+    if !attribution.is_empty() {
+        attribution.insert(0, XMLElem::Text("— ".to_owned()));
+        figure.push(xml!(figcaption [class="quote-attribution"] attribution));
+    }
+    Ok(xml!(figure [class="quote"] figure).nosnippet())
+}
+
+fn execute_q(
+    ctx: Context,
+    attrs: HashMap<String, ValueAst>,
+    inner: Vec<TextElemAst>,
+) -> EResult<XMLElem> {
+    let mut q_attrs = Vec::new();
+    if let Some(cite) = verify_cite_url(&ctx, &attrs)? {
+        q_attrs.push(xml::Attr::Pair("cite".to_owned(), cite));
+    }
+    Ok(XMLElem::WithElem("q".to_owned(), q_attrs, process_text(ctx, inner)?).nosnippet())
+}
+
+// 属性を持たないインライン要素
+fn execute_inline(ctx: Context, tag: &str, inner: Vec<TextElemAst>) -> EResult<XMLElem> {
+    Ok(XMLElem::WithElem(
+        tag.to_owned(),
+        Vec::new(),
+        process_text(ctx, inner)?,
+    ))
+}
+
+fn execute_abbr(
+    ctx: Context,
+    attrs: HashMap<String, ValueAst>,
+    inner: Vec<TextElemAst>,
+) -> EResult<XMLElem> {
+    let title = value_utils::get_str(&attrs, "title", &ctx.location)?;
+    Ok(xml!(abbr [title=title] process_text(ctx, inner)?))
+}
+
+fn execute_ruby(
+    ctx: Context,
+    attrs: HashMap<String, ValueAst>,
+    inner: Vec<TextElemAst>,
+) -> EResult<XMLElem> {
+    let rt = value_utils::get_str(&attrs, "rt", &ctx.location)?;
+    let mut ruby = process_text(ctx, inner)?;
+    ruby.push(xml!(rp [] [xml!("(".to_owned())]));
+    ruby.push(xml!(rt [] [xml!(rt.to_owned())]));
+    ruby.push(xml!(rp [] [xml!(")".to_owned())]));
+    Ok(xml!(ruby [] ruby))
+}
+
+fn execute_link(
     ctx: Context,
     attrs: HashMap<String, ValueAst>,
     inner: Vec<TextElemAst>,
@@ -629,6 +1755,30 @@ fn execute_link(
         .collect::<EResult<Vec<_>>>()?))
 }
 
+// ヘッダやナビゲーションに出すタイトル。\footnoteはページの注釈にせず取り除く
+fn process_title(ctx: &Context, title: &[TextElemAst]) -> EResult<Vec<XMLElem>> {
+    strip_footnotes(title)
+        .into_iter()
+        .map(|(e, loc)| process_text_elem(ctx.fork_with_loc(loc), e))
+        .collect()
+}
+
+fn strip_footnotes(text: &[TextElemAst]) -> Vec<TextElemAst> {
+    text.iter()
+        .filter_map(|(e, loc)| match e {
+            TextElem::Cmd(cmd) if cmd.name == "footnote" => None,
+            TextElem::Cmd(cmd) => Some((
+                TextElem::Cmd(Cmd {
+                    inner: strip_footnotes(&cmd.inner),
+                    ..cmd.clone()
+                }),
+                loc.to_owned(),
+            )),
+            _ => Some((e.to_owned(), loc.to_owned())),
+        })
+        .collect()
+}
+
 fn execute_n(ctx: Context, inner: Vec<TextElemAst>) -> EResult<XMLElem> {
     Ok(xml!(div [] inner.into_iter()
         .map(|(e, loc)| process_text_elem(ctx.fork_with_loc(loc), e))
@@ -648,7 +1798,7 @@ fn execute_articles(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<X
                 let href_path = resolve_link(Path::new(path), ctx.path)?;
                 Ok(xml!(li [class="articles-elem"] [xml!(a
                     [href=href_path.to_str().unwrap()]
-                    title.iter().map(|(e,loc)| process_text_elem(ctx.fork_with_loc(loc.to_owned()), e.clone())).collect::<EResult<Vec<_>>>()?
+                    process_title(&ctx, title)?
                 )]))
             })
             .collect::<EResult<Vec<_>>>())
@@ -656,35 +1806,476 @@ fn execute_articles(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<X
     ))
 }
 
+pub const SNIPPETS_DIR: &str = "snippets";
+
+// \blockcode file=...の探索順。記事からの相対パス、サイトのルート、snippetsディレクトリ
+pub fn snippet_candidates(loc: &Location, file: &str, from: &Path) -> EResult<Vec<PathBuf>> {
+    let mut candidates = vec![PathBuf::from(absolute(loc, file, from)?)];
+    if let Some(file) = verify_link(file) {
+        if !file.starts_with("../") {
+            candidates.push(PathBuf::from(&file));
+            candidates.push(Path::new(SNIPPETS_DIR).join(&file));
+        }
+    }
+    Ok(candidates)
+}
+
+fn is_anchor_line(line: &str) -> bool {
+    line.contains("ANCHOR:") || line.contains("ANCHOR_END:")
+}
+
+// lines="a-b"(1始まり、両端を含む)またはregion="name"で切り出す。
+// regionは"ANCHOR: name"から"ANCHOR_END: name"までで、マーカー行は出力しない。
+// 各行には元のファイルでの行番号を付け、前後の空行は除く
+fn extract_snippet<'a>(
+    src: &'a str,
+    lines: Option<&str>,
+    region: Option<&str>,
+) -> Result<Vec<(usize, &'a str)>, String> {
+    let all = src
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line))
+        .collect::<Vec<_>>();
+    let selected = match (lines, region) {
+        (Some(_), Some(_)) => return Err("lines and region cannot be used together".to_owned()),
+        (Some(range), None) => {
+            let parse = |n: &str| {
+                n.trim()
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid line range \"{}\"", range))
+            };
+            let mut bounds = range.split('-');
+            let from = parse(bounds.next().unwrap_or(""))?;
+            let to = match bounds.next() {
+                None => from,
+                Some(to) if to.trim().is_empty() => all.len(),
+                Some(to) => parse(to)?,
+            };
+            if bounds.next().is_some() || from == 0 || from > to {
+                return Err(format!("invalid line range \"{}\"", range));
+            }
+            if to > all.len() {
+                return Err(format!(
+                    "line range \"{}\" exceeds the file with {} lines",
+                    range,
+                    all.len()
+                ));
+            }
+            all[from - 1..to].to_vec()
+        }
+        (None, Some(name)) => {
+            let is_marker = |line: &str, marker: &str| {
+                line.find(marker)
+                    .map(|idx| line[idx + marker.len()..].trim() == name)
+                    .unwrap_or(false)
+            };
+            let begin = all
+                .iter()
+                .position(|(_, l)| is_marker(l, "ANCHOR:"))
+                .ok_or_else(|| format!("region \"{}\" not found", name))?;
+            let end = all[begin..]
+                .iter()
+                .position(|(_, l)| is_marker(l, "ANCHOR_END:"))
+                .ok_or_else(|| format!("region \"{}\" is not closed", name))?;
+            all[begin + 1..begin + end].to_vec()
+        }
+        (None, None) => all,
+    };
+    let selected = selected
+        .into_iter()
+        .filter(|(_, line)| !is_anchor_line(line))
+        .collect::<Vec<_>>();
+    // strip_indentが除く前後の空行をここで除き、行番号とずれないようにする
+    let is_blank = |(_, line): &&(usize, &str)| line.trim().is_empty();
+    let head = selected.iter().take_while(is_blank).count();
+    let tail = selected
+        .iter()
+        .skip(head)
+        .rev()
+        .take_while(is_blank)
+        .count();
+    Ok(selected[head..selected.len() - tail].to_vec())
+}
+
+// 切り出したソースと、その各行の元のファイルでの行番号
+fn load_snippet(
+    ctx: &Context,
+    attrs: &HashMap<String, ValueAst>,
+    file: &str,
+) -> EResult<(String, Vec<usize>)> {
+    let src = snippet_candidates(&ctx.location, file, ctx.path)?
+        .iter()
+        .find_map(|path| ctx.snippets.get(path))
+        .ok_or_else(|| Error::InvalidLink {
+            link: PathBuf::from(file),
+            msg: "snippet file not found".to_owned(),
+            loc: Box::new(ctx.location.clone()),
+        })?;
+    let lines = value_utils::verify_str(attrs, "lines", &ctx.location)?;
+    let region = value_utils::verify_str(attrs, "region", &ctx.location)?;
+    let selected = extract_snippet(src, lines, region).map_err(|desc| Error::ProcessError {
+        loc: ctx.location.clone(),
+        desc,
+    })?;
+    Ok((
+        selected
+            .iter()
+            .map(|(_, line)| *line)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        selected.iter().map(|(n, _)| *n).collect(),
+    ))
+}
+
 fn execute_blockcode(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
-    let src = value_utils::get_str(&attrs, "src", &ctx.location)?;
-    let lang = value_utils::get_str(&attrs, "lang", &ctx.location)?;
-    let lines = src.split('\n').collect::<Vec<_>>();
-    let white = regex::Regex::new(r"^[ \t\r\n]*$").unwrap();
-    assert!(!white.is_match("abc"));
-    let empty_line_cnt_from_head = lines.iter().take_while(|l| white.is_match(l)).count();
-    let empty_line_cnt_from_tail = lines.iter().rev().take_while(|l| white.is_match(l)).count();
-    let mut padding_n = 100_000;
-    for line in &lines {
-        if white.is_match(line) {
-            continue;
-        }
-        padding_n = padding_n.min(line.chars().take_while(|c| *c == ' ').count());
-    }
-    let mut code = Vec::new();
-    for line in lines[empty_line_cnt_from_head..lines.len() - empty_line_cnt_from_tail].to_vec() {
-        code.push(line.get(padding_n..).unwrap_or(""));
-    }
-    if let Some(sr) = ctx.ss.find_syntax_by_extension(&lang) {
-        let mut generator = ClassedHTMLGenerator::new(sr, ctx.ss);
-        for line in code {
-            generator.parse_html_for_line(&line);
-        }
-        Ok(xml!(code [] [xml!(pre [] [XMLElem::Raw(generator.finalize())])]))
+    let file = value_utils::verify_str(&attrs, "file", &ctx.location)?;
+    let before = value_utils::verify_str(&attrs, "before", &ctx.location)?;
+    let after = value_utils::verify_str(&attrs, "after", &ctx.location)?;
+    // ファイルから切り出した場合は元のファイルの行番号も得る
+    let (src, file_linenos) = match (before, after, file) {
+        (Some(before), Some(after), None) if !attrs.contains_key("src") => (
+            diff::unified(
+                &strip_indent(before).join("\n"),
+                &strip_indent(after).join("\n"),
+                3,
+            )
+            .join("\n"),
+            Vec::new(),
+        ),
+        (Some(_), _, _) | (_, Some(_), _) => return Err(Error::ProcessError {
+            loc: ctx.location,
+            desc: "\\blockcode needs both before and after, and cannot have src or file with them."
+                .to_owned(),
+        }),
+        (None, None, Some(file)) => {
+            if attrs.contains_key("src") {
+                return Err(Error::ProcessError {
+                    loc: ctx.location,
+                    desc: "\\blockcode cannot have both src and file.".to_owned(),
+                });
+            }
+            load_snippet(&ctx, &attrs, file)?
+        }
+        (None, None, None) => (
+            value_utils::get_str(&attrs, "src", &ctx.location)?.to_owned(),
+            Vec::new(),
+        ),
+    };
+    // ファイルから読む場合は拡張子を言語名の既定値にする
+    let lang = match (
+        value_utils::verify_str(&attrs, "lang", &ctx.location)?,
+        file,
+    ) {
+        (Some(lang), _) => lang,
+        (None, Some(file)) => Path::new(file)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or(""),
+        (None, None) => value_utils::get_str(&attrs, "lang", &ctx.location)?,
+    };
+    // "diff"または"diff:toml"のように差分の中身の言語を指定する
+    let (is_diff, lang) = if let Some(inner) = lang.strip_prefix("diff:") {
+        (true, inner)
+    } else if lang == "diff" {
+        (true, "")
+    } else {
+        (before.is_some(), lang)
+    };
+    let caption = value_utils::verify_text(&attrs, "caption", &ctx.location)?;
+    let filename = value_utils::verify_str(&attrs, "filename", &ctx.location)?;
+    let id = value_utils::verify_str(&attrs, "id", &ctx.location)?;
+    let start = value_utils::verify_int(&attrs, "start", &ctx.location)?;
+    let linenos = value_utils::verify_int(&attrs, "linenos", &ctx.location)?.unwrap_or(0) != 0
+        || start.is_some();
+    // startが無ければファイルの行番号を使う
+    let file_linenos = if start.is_some() {
+        Vec::new()
+    } else {
+        file_linenos
+    };
+    let start = start.unwrap_or(1).max(0) as usize;
+    let highlight = match value_utils::verify_str(&attrs, "highlight", &ctx.location)? {
+        Some(spec) => parse_line_set(spec).ok_or_else(|| Error::InvalidAttribute {
+            name: "highlight".to_owned(),
+            reason: "highlight must be comma separated line numbers or ranges like \"1,3-5\""
+                .to_owned(),
+            loc: Box::new(ctx.location.clone()),
+        })?,
+        None => Vec::new(),
+    };
+    let number = if caption.is_some() || id.is_some() {
+        Some(caption_number(&ctx, "blockcode")?)
+    } else {
+        None
+    };
+    // 生成した差分は字下げされておらず、末尾の空行も変更なしの行なので除かない
+    let code = if before.is_some() {
+        src.lines().collect()
+    } else {
+        strip_indent(&src)
+    };
+    let diff_lines = if is_diff {
+        split_diff_lines(&code)
+    } else {
+        Vec::new()
+    };
+    let code = if is_diff {
+        diff_lines.iter().map(|(_, _, body)| *body).collect()
+    } else {
+        code
+    };
+    let lines = if let Some(sr) = ctx.ss.find_syntax_by_token(&lang) {
+        highlight_lines(ctx.ss, sr, &ctx.config.highlight.class_prefix, &code)
+    } else {
+        if !(is_diff && lang.is_empty()) {
+            match closest_syntax(ctx.ss, &lang) {
+                Some(token) => warn!(
+                    "{} missing syntax for {}. did you mean {}?",
+                    ctx.location, lang, token
+                ),
+                None => warn!("{} missing syntax for {}", ctx.location, lang),
+            }
+        }
+        code.iter().map(|line| escape_html(line)).collect()
+    };
+    let lines = lines
+        .into_iter()
+        .enumerate()
+        .map(|(idx, line)| {
+            let n = file_linenos.get(idx).copied().unwrap_or(start + idx);
+            let emphasized = highlight.iter().any(|(from, to)| *from <= n && n <= *to);
+            let (diff_class, line) = match diff_lines.get(idx) {
+                Some((class, marker, _)) => (
+                    *class,
+                    format!(
+                        "<span class=\"diff-marker\" data-nosnippet>{}</span>{}",
+                        escape_html(marker),
+                        line
+                    ),
+                ),
+                None => ("", line),
+            };
+            let number = if linenos {
+                format!("<span class=\"line-number\" data-nosnippet>{}</span>", n)
+            } else {
+                String::new()
+            };
+            format!(
+                "<span class=\"{}{}\">{}{}</span>",
+                if emphasized {
+                    "line highlighted"
+                } else {
+                    "line"
+                },
+                diff_class,
+                number,
+                line
+            )
+        })
+        .collect::<Vec<_>>();
+    let pre_class = format!("{}code", ctx.config.highlight.class_prefix);
+    let code = xml!(code [] [xml!(pre [class=pre_class] [XMLElem::Raw(lines.join("\n"))])]);
+    if number.is_none() && filename.is_none() {
+        return Ok(code);
+    }
+    let mut caption_xml = Vec::new();
+    caption_xml.extend(number);
+    if let Some(caption) = caption {
+        caption_xml.append(&mut process_text(ctx.clone(), caption.to_vec())?);
+    }
+    if let Some(filename) = filename {
+        caption_xml.push(xml!(
+            span[class = "listing-filename"][xml!(filename.to_owned())]
+        ));
+    }
+    let inner = vec![xml!(figcaption [class="listing-caption"] caption_xml), code];
+    if let Some(id) = id {
+        Ok(xml!(figure [class="listing", id=id] inner))
+    } else {
+        Ok(xml!(figure [class="listing"] inner))
+    }
+}
+
+// 隣り合う文字の入れ替えも1回と数える編集距離
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j - 1] + cost)
+                .min(d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+// 言語名か拡張子のうち綴りが近いもの。近いものが無ければNone
+fn closest_syntax(ss: &SyntaxSet, lang: &str) -> Option<String> {
+    let lang = lang.to_lowercase();
+    ss.syntaxes()
+        .iter()
+        .flat_map(|syntax| {
+            std::iter::once(syntax.name.to_lowercase()).chain(syntax.file_extensions.clone())
+        })
+        .map(|token| {
+            let distance = edit_distance(&lang, &token.to_lowercase());
+            let len_diff = (token.chars().count() as isize - lang.chars().count() as isize).abs();
+            (distance, len_diff, token)
+        })
+        .filter(|(distance, _, _)| *distance <= (lang.chars().count() / 3).max(1))
+        .min()
+        .map(|(_, _, token)| token)
+}
+
+// 差分の1行を(クラス, 記号, 本体)に分ける
+// 先頭の"---"と"+++"の組だけをファイルのヘッダとして扱う
+fn split_diff_lines<'a>(lines: &[&'a str]) -> Vec<(&'static str, &'a str, &'a str)> {
+    let headers = match lines {
+        [from, to, ..] if from.starts_with("--- ") && to.starts_with("+++ ") => 2,
+        _ => 0,
+    };
+    lines
+        .iter()
+        .enumerate()
+        .map(|(idx, line)| {
+            if idx < headers {
+                (" diff-header", *line, "")
+            } else {
+                split_diff_line(line)
+            }
+        })
+        .collect()
+}
+
+fn split_diff_line(line: &str) -> (&'static str, &str, &str) {
+    if line.starts_with("@@") {
+        (" diff-hunk", line, "")
+    } else if let Some(body) = line.strip_prefix('+') {
+        (" diff-add", "+", body)
+    } else if let Some(body) = line.strip_prefix('-') {
+        (" diff-del", "-", body)
     } else {
-        warn!("missing syntax for {}", lang);
-        Ok(xml!(code [] [xml!(pre [] [XMLElem::Raw(code.join("\n"))])]))
+        ("", " ", line.strip_prefix(' ').unwrap_or(line))
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+        .replace("\"", "&quot;")
+}
+
+// "1,3-5"のような行番号の集合を(開始, 終了)の列にする
+fn parse_line_set(spec: &str) -> Option<Vec<(usize, usize)>> {
+    spec.split(',')
+        .map(|part| {
+            let mut bounds = part.split('-');
+            let from = bounds.next()?.trim().parse::<usize>().ok()?;
+            let to = match bounds.next() {
+                Some(to) => to.trim().parse::<usize>().ok()?,
+                None => from,
+            };
+            if bounds.next().is_some() || from > to {
+                None
+            } else {
+                Some((from, to))
+            }
+        })
+        .collect()
+}
+
+// 前後の空行を落とし、空白でない行に共通する先頭の空白(タブを含む)を取り除く
+fn strip_indent(src: &str) -> Vec<&str> {
+    let lines = src.lines().collect::<Vec<_>>();
+    let is_blank = |line: &str| line.trim().is_empty();
+    let head = lines.iter().take_while(|line| is_blank(line)).count();
+    let tail = lines
+        .iter()
+        .skip(head)
+        .rev()
+        .take_while(|line| is_blank(line))
+        .count();
+    let lines = &lines[head..lines.len() - tail];
+    let mut prefix: Option<&str> = None;
+    for line in lines.iter().filter(|line| !is_blank(line)) {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        prefix = Some(match prefix {
+            None => indent,
+            Some(prefix) => {
+                let common = prefix
+                    .char_indices()
+                    .zip(indent.chars())
+                    .find(|((_, a), b)| a != b)
+                    .map(|((idx, _), _)| idx)
+                    .unwrap_or_else(|| prefix.len().min(indent.len()));
+                &prefix[..common]
+            }
+        });
     }
+    let prefix = prefix.unwrap_or("");
+    lines
+        .iter()
+        .map(|line| {
+            line.strip_prefix(prefix)
+                .unwrap_or_else(|| line.trim_start())
+        })
+        .collect()
+}
+
+// 行ごとに<span>の対応が閉じたHTMLを生成する。
+// 前の行から続くスコープは行頭で開き直し、行末で閉じる
+fn highlight_lines(
+    ss: &SyntaxSet,
+    syntax: &SyntaxReference,
+    prefix: &str,
+    code: &[&str],
+) -> Vec<String> {
+    let mut parse_state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let open_span = |scope: &Scope| {
+        format!(
+            "<span class=\"{}\">",
+            scope
+                .build_string()
+                .split('.')
+                .map(|atom| format!("{}{}", prefix, atom))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    };
+    code.iter()
+        .map(|line| {
+            let mut html = stack.as_slice().iter().map(open_span).collect::<String>();
+            let mut cur = 0;
+            for (idx, op) in parse_state.parse_line(line, ss) {
+                if idx > cur {
+                    html.push_str(&escape_html(&line[cur..idx]));
+                    cur = idx;
+                }
+                stack.apply_with_hook(&op, |basic_op, _| match basic_op {
+                    BasicScopeStackOp::Push(scope) => html.push_str(&open_span(&scope)),
+                    BasicScopeStackOp::Pop => html.push_str("</span>"),
+                });
+            }
+            html.push_str(&escape_html(&line[cur..]));
+            html.push_str(&"</span>".repeat(stack.len()));
+            html
+        })
+        .collect()
 }
 extern crate hex;
 fn process_inlinestr(_: Context, s: String) -> XMLElem {
@@ -724,16 +2315,30 @@ fn execute_profile(
     ]))
 }
 
+// AMPでは<iframe>を使えないのでamp-iframeにする。
+// amp-iframeはHTTPSのsrcと、領域を確保するための大きさを必要とする
 fn execute_iframe(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
-    let attrs = [
-        (
-            "width",
-            value_utils::verify_int(&attrs, "width", &ctx.location)?.map(|i| i.to_string()),
-        ),
-        (
-            "height",
-            value_utils::verify_int(&attrs, "height", &ctx.location)?.map(|i| i.to_string()),
+    let src = value_utils::get_str(&attrs, "src", &ctx.location)?;
+    if !src.starts_with("https://") {
+        return Err(Error::InvalidAttribute {
+            name: "src".to_owned(),
+            reason: "amp-iframe requires an https URL".to_owned(),
+            loc: Box::new(ctx.location.clone()),
+        });
+    }
+    let width = value_utils::get_int(&attrs, "width", &ctx.location)?;
+    let height = value_utils::get_int(&attrs, "height", &ctx.location)?;
+    let mut iframe_attrs = vec![
+        xml::Attr::Pair("src".to_owned(), src.to_owned()),
+        xml::Attr::Pair("width".to_owned(), width.to_string()),
+        xml::Attr::Pair("height".to_owned(), height.to_string()),
+        xml::Attr::Pair("layout".to_owned(), "responsive".to_owned()),
+        xml::Attr::Pair(
+            "sandbox".to_owned(),
+            "allow-scripts allow-same-origin allow-popups".to_owned(),
         ),
+    ];
+    let optional = [
         (
             "frameborder",
             value_utils::verify_int(&attrs, "frameborder", &ctx.location)?.map(|i| i.to_string()),
@@ -746,19 +2351,245 @@ fn execute_iframe(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XML
             "scrolling",
             value_utils::verify_str(&attrs, "scrolling", &ctx.location)?.map(|s| s.to_string()),
         ),
-        (
-            "src",
-            value_utils::verify_str(&attrs, "src", &ctx.location)?.map(|s| s.to_string()),
-        ),
-    ]
-    .iter()
-    .filter_map(|(name, value)| {
+    ];
+    iframe_attrs.extend(optional.iter().filter_map(|(name, value)| {
         value
             .as_ref()
             .map(|value| xml::Attr::Pair(name.to_owned().to_owned(), value.to_owned()))
-    })
-    .collect::<Vec<xml::Attr>>();
-    Ok(XMLElem::Single("iframe".to_owned(), attrs))
+    }));
+    // ページ先頭付近のamp-iframeはプレースホルダが無いと表示されない
+    let placeholder = XMLElem::WithElem(
+        "a".to_owned(),
+        vec![
+            xml::Attr::Single("placeholder".to_owned()),
+            xml::Attr::Pair("href".to_owned(), src.to_owned()),
+        ],
+        vec![xml!(src.to_owned())],
+    );
+    Ok(XMLElem::WithElem(
+        "amp-iframe".to_owned(),
+        iframe_attrs,
+        vec![placeholder],
+    ))
+}
+
+// \video, \audioで扱う形式とMIMEタイプ
+pub fn media_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "mp4" | "m4v" => Some("video/mp4"),
+        "webm" => Some("video/webm"),
+        "ogv" => Some("video/ogg"),
+        "mp3" => Some("audio/mpeg"),
+        "m4a" => Some("audio/mp4"),
+        "ogg" | "oga" | "opus" => Some("audio/ogg"),
+        "wav" => Some("audio/wav"),
+        "flac" => Some("audio/flac"),
+        _ => None,
+    }
+}
+
+// 真偽値の属性は0以外を真とする
+fn flag_attrs(
+    attrs: &HashMap<String, ValueAst>,
+    names: &[&str],
+    loc: &Location,
+) -> EResult<Vec<xml::Attr>> {
+    let mut flags = Vec::new();
+    for name in names {
+        if value_utils::verify_int(attrs, name, loc)?.unwrap_or(0) != 0 {
+            flags.push(xml::Attr::Single((*name).to_owned()));
+        }
+    }
+    Ok(flags)
+}
+
+// サイト内のメディアファイルを探し、<source>を作る
+fn media_source(ctx: &Context, src: &str, kind: &str) -> EResult<XMLElem> {
+    let invalid = |msg: String| Error::InvalidLink {
+        msg,
+        loc: Box::new(ctx.location.clone()),
+        link: PathBuf::from(src),
+    };
+    let mime = media_type(Path::new(src))
+        .filter(|mime| mime.starts_with(kind))
+        .ok_or_else(|| invalid(format!("unsupported {} format", kind)))?;
+    if !is_http_url(src)
+        && !ctx
+            .media
+            .contains(Path::new(&absolute(&ctx.location, src, ctx.path)?))
+    {
+        return Err(invalid(format!("local {} not found", kind)));
+    }
+    Ok(XMLElem::Single(
+        "source".to_owned(),
+        vec![
+            xml::Attr::Pair("src".to_owned(), src.to_owned()),
+            xml::Attr::Pair("type".to_owned(), mime.to_owned()),
+        ],
+    ))
+}
+
+fn media_fallback(kind: &str) -> XMLElem {
+    XMLElem::WithElem(
+        "div".to_owned(),
+        vec![xml::Attr::Single("fallback".to_owned())],
+        vec![xml!(p [] [xml!(format!("このブラウザは{}の再生に対応していません", kind))])],
+    )
+}
+
+fn execute_video(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
+    let src = value_utils::get_str(&attrs, "src", &ctx.location)?;
+    let poster = value_utils::verify_str(&attrs, "poster", &ctx.location)?;
+    let w = value_utils::verify_int(&attrs, "w", &ctx.location)?;
+    let h = value_utils::verify_int(&attrs, "h", &ctx.location)?;
+    let source = media_source(&ctx, src, "video")?;
+    let poster_size = match poster {
+        Some(poster) if !is_http_url(poster) => Some(
+            ctx.aspects
+                .get(Path::new(&absolute(&ctx.location, poster, ctx.path)?))
+                .copied()
+                .ok_or_else(|| Error::InvalidLink {
+                    msg: "poster image not found".to_owned(),
+                    loc: Box::new(ctx.location.clone()),
+                    link: PathBuf::from(poster),
+                })?,
+        ),
+        _ => None,
+    };
+    // AMPは領域を先に確保するので大きさが必要。無ければポスター画像の大きさを使う
+    let (w, h) = match (w, h, poster_size) {
+        (Some(w), Some(h), _) => (w as usize, h as usize),
+        (_, _, Some(size)) => size,
+        _ => {
+            return Err(Error::ProcessError {
+                loc: ctx.location.clone(),
+                desc: "\\video needs w and h or a local poster image".to_owned(),
+            })
+        }
+    };
+    let mut video_attrs = vec![
+        xml::Attr::Pair("width".to_owned(), w.to_string()),
+        xml::Attr::Pair("height".to_owned(), h.to_string()),
+        xml::Attr::Pair("layout".to_owned(), "responsive".to_owned()),
+        xml::Attr::Single("controls".to_owned()),
+    ];
+    if let Some(poster) = poster {
+        video_attrs.push(xml::Attr::Pair("poster".to_owned(), img_src(&ctx, poster)?));
+    }
+    video_attrs.append(&mut flag_attrs(
+        &attrs,
+        &["autoplay", "loop", "muted"],
+        &ctx.location,
+    )?);
+    Ok(XMLElem::WithElem(
+        "amp-video".to_owned(),
+        video_attrs,
+        vec![source, media_fallback("動画")],
+    ))
+}
+
+fn execute_audio(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
+    let src = value_utils::get_str(&attrs, "src", &ctx.location)?;
+    let source = media_source(&ctx, src, "audio")?;
+    let mut audio_attrs = vec![
+        xml::Attr::Pair("width".to_owned(), "auto".to_owned()),
+        xml::Attr::Pair("height".to_owned(), "50".to_owned()),
+        xml::Attr::Pair("layout".to_owned(), "fixed-height".to_owned()),
+        xml::Attr::Single("controls".to_owned()),
+    ];
+    if let Some(title) = value_utils::verify_str(&attrs, "title", &ctx.location)? {
+        audio_attrs.push(xml::Attr::Pair("title".to_owned(), title.to_owned()));
+    }
+    audio_attrs.append(&mut flag_attrs(
+        &attrs,
+        &["autoplay", "loop", "muted"],
+        &ctx.location,
+    )?);
+    Ok(XMLElem::WithElem(
+        "amp-audio".to_owned(),
+        audio_attrs,
+        vec![source, media_fallback("音声")],
+    ))
+}
+
+// 埋め込みのIDはそのまま属性に入るので形式を確かめておく
+fn get_embed_id<'a>(
+    attrs: &'a HashMap<String, ValueAst>,
+    pattern: &str,
+    loc: &Location,
+) -> EResult<&'a str> {
+    let id = value_utils::get_str(attrs, "id", loc)?;
+    if regex::Regex::new(pattern).unwrap().is_match(id) {
+        Ok(id)
+    } else {
+        Err(Error::InvalidAttribute {
+            name: "id".to_owned(),
+            reason: format!("id must match {}", pattern),
+            loc: Box::new(loc.to_owned()),
+        })
+    }
+}
+
+// 埋め込みの既定の大きさ。縦横比だけが意味を持つ
+fn embed_size(
+    attrs: &HashMap<String, ValueAst>,
+    default: (i64, i64),
+    loc: &Location,
+) -> EResult<Vec<xml::Attr>> {
+    let w = value_utils::verify_int(attrs, "w", loc)?.unwrap_or(default.0);
+    let h = value_utils::verify_int(attrs, "h", loc)?.unwrap_or(default.1);
+    Ok(vec![
+        xml::Attr::Pair("width".to_owned(), w.to_string()),
+        xml::Attr::Pair("height".to_owned(), h.to_string()),
+        xml::Attr::Pair("layout".to_owned(), "responsive".to_owned()),
+    ])
+}
+
+fn execute_youtube(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
+    let id = get_embed_id(&attrs, r"^[A-Za-z0-9_-]{11}$", &ctx.location)?;
+    let mut youtube_attrs = vec![xml::Attr::Pair("data-videoid".to_owned(), id.to_owned())];
+    youtube_attrs.append(&mut embed_size(&attrs, (480, 270), &ctx.location)?);
+    Ok(XMLElem::WithElem(
+        "amp-youtube".to_owned(),
+        youtube_attrs,
+        Vec::new(),
+    ))
+}
+
+fn execute_twitter(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
+    let id = get_embed_id(&attrs, r"^[0-9]+$", &ctx.location)?;
+    let mut twitter_attrs = vec![xml::Attr::Pair("data-tweetid".to_owned(), id.to_owned())];
+    twitter_attrs.append(&mut embed_size(&attrs, (375, 472), &ctx.location)?);
+    // 読み込まれるまではツイートへのリンクを表示する
+    let url = format!("https://twitter.com/i/status/{}", id);
+    Ok(XMLElem::WithElem(
+        "amp-twitter".to_owned(),
+        twitter_attrs,
+        vec![XMLElem::WithElem(
+            "blockquote".to_owned(),
+            vec![xml::Attr::Single("placeholder".to_owned())],
+            vec![xml!(a[href = url.clone()][xml!(url)])],
+        )],
+    ))
+}
+
+fn execute_gist(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
+    let id = get_embed_id(&attrs, r"^[0-9a-f]+$", &ctx.location)?;
+    let h = value_utils::verify_int(&attrs, "h", &ctx.location)?.unwrap_or(225);
+    let mut gist_attrs = vec![
+        xml::Attr::Pair("data-gistid".to_owned(), id.to_owned()),
+        xml::Attr::Pair("layout".to_owned(), "fixed-height".to_owned()),
+        xml::Attr::Pair("height".to_owned(), h.to_string()),
+    ];
+    if let Some(file) = value_utils::verify_str(&attrs, "file", &ctx.location)? {
+        gist_attrs.push(xml::Attr::Pair("data-file".to_owned(), file.to_owned()));
+    }
+    Ok(XMLElem::WithElem(
+        "amp-gist".to_owned(),
+        gist_attrs,
+        Vec::new(),
+    ))
 }
 
 fn execute_figure(
@@ -768,6 +2599,7 @@ fn execute_figure(
 ) -> EResult<XMLElem> {
     let caption = value_utils::get_text(&attrs, "caption", &ctx.location)?.to_vec();
     let id = value_utils::verify_str(&attrs, "id", &ctx.location)?;
+    let mut caption_xml = vec![caption_number(&ctx, "figure")?];
     let figures = inner
         .iter()
         .map(|(e, loc)| {
@@ -788,10 +2620,8 @@ fn execute_figure(
             }
         })
         .collect::<EResult<Vec<XMLElem>>>()?;
-    let inner = vec![
-        xml!(div [] figures),
-        xml!(figcaption [] process_text(ctx, caption)?),
-    ];
+    caption_xml.append(&mut process_text(ctx, caption)?);
+    let inner = vec![xml!(div [] figures), xml!(figcaption [] caption_xml)];
     if let Some(id) = id {
         Ok(xml!(figure [id=id] inner))
     } else {
@@ -799,6 +2629,511 @@ fn execute_figure(
     }
 }
 
+// 一覧は正方形に切り抜いた縮小版を表示し、選ぶとamp-lightbox-galleryで元画像を開く
+fn gallery_item(ctx: Context, attrs: HashMap<String, ValueAst>, columns: i64) -> EResult<XMLElem> {
+    let url = value_utils::get_str(&attrs, "url", &ctx.location)?;
+    let alt = value_utils::get_str(&attrs, "alt", &ctx.location)?;
+    let caption = value_utils::verify_text(&attrs, "caption", &ctx.location)?;
+    let local_path = if is_http_url(url) {
+        PathBuf::new()
+    } else {
+        PathBuf::from(absolute(&ctx.location, url, ctx.path)?)
+    };
+    let width = if is_http_url(url) {
+        0
+    } else {
+        ctx.aspects
+            .get(&local_path)
+            .map(|size| size.0)
+            .ok_or_else(|| Error::InvalidLink {
+                msg: "local image not found".to_owned(),
+                loc: Box::new(ctx.location.clone()),
+                link: PathBuf::from(url),
+            })?
+    };
+    let src = img_src(&ctx, url)?;
+    let srcset = img_srcset(url, &src, width, true);
+    let mut img_attrs = vec![
+        xml::Attr::Pair("src".to_owned(), src.clone()),
+        xml::Attr::Pair("alt".to_owned(), alt.to_owned()),
+        xml::Attr::Pair("width".to_owned(), "1".to_owned()),
+        xml::Attr::Pair("height".to_owned(), "1".to_owned()),
+        xml::Attr::Pair("layout".to_owned(), "responsive".to_owned()),
+        xml::Attr::Pair("object-fit".to_owned(), "cover".to_owned()),
+        xml::Attr::Single("lightbox".to_owned()),
+    ];
+    if !srcset.is_empty() {
+        img_attrs.push(xml::Attr::Pair("srcset".to_owned(), srcset.join(", ")));
+        img_attrs.push(xml::Attr::Pair(
+            "sizes".to_owned(),
+            format!("{}vw", 100 / columns),
+        ));
+    }
+    let img = XMLElem::WithElem(
+        "amp-img".to_owned(),
+        img_attrs,
+        img_placeholder(&ctx, &local_path).into_iter().collect(),
+    );
+    let mut item = vec![img];
+    // amp-lightbox-galleryはfigcaptionを拡大表示時のキャプションに使う
+    if let Some(caption) = caption {
+        item.push(
+            xml!(figcaption [class="gallery-item-caption"] process_text(ctx, caption.to_vec())?),
+        );
+    }
+    Ok(xml!(figure [class="gallery-item"] item))
+}
+
+fn execute_gallery(
+    ctx: Context,
+    attrs: HashMap<String, ValueAst>,
+    inner: Vec<TextElemAst>,
+) -> EResult<XMLElem> {
+    let caption = value_utils::verify_text(&attrs, "caption", &ctx.location)?;
+    let id = value_utils::verify_str(&attrs, "id", &ctx.location)?;
+    let columns = value_utils::verify_int(&attrs, "columns", &ctx.location)?.unwrap_or(3);
+    if columns < 1 {
+        return Err(Error::InvalidAttribute {
+            name: "columns".to_owned(),
+            reason: "columns must be positive".to_owned(),
+            loc: Box::new(ctx.location.clone()),
+        });
+    }
+    let number = if caption.is_some() || id.is_some() {
+        Some(caption_number(&ctx, "gallery")?)
+    } else {
+        None
+    };
+    let items = inner
+        .into_iter()
+        .map(|(e, loc)| match e {
+            TextElem::Cmd(cmd) if cmd.name.as_str() == "img" => {
+                gallery_item(ctx.fork_with_loc(loc), cmd.attrs, columns)
+            }
+            _ => Err(Error::ProcessError {
+                loc,
+                desc: "\\gallery can only have \\img as child element.".to_owned(),
+            }),
+        })
+        .collect::<EResult<Vec<XMLElem>>>()?;
+    let grid_style = format!("grid-template-columns: repeat({}, 1fr)", columns);
+    let mut inner = vec![xml!(div [class="gallery-grid", style=grid_style] items)];
+    if number.is_some() || caption.is_some() {
+        let mut caption_xml = number.into_iter().collect::<Vec<_>>();
+        if let Some(caption) = caption {
+            caption_xml.append(&mut process_text(ctx, caption.to_vec())?);
+        }
+        inner.push(xml!(figcaption [] caption_xml));
+    }
+    if let Some(id) = id {
+        Ok(xml!(figure [class="gallery", id=id] inner))
+    } else {
+        Ok(xml!(figure [class="gallery"] inner))
+    }
+}
+
+fn execute_footnote(ctx: Context, inner: Vec<TextElemAst>) -> EResult<XMLElem> {
+    let note = process_text(ctx.clone(), inner)?;
+    let n = {
+        let mut page = ctx.page.borrow_mut();
+        page.footnotes.push(note.clone());
+        page.footnotes.len()
+    };
+    if ctx.sidenote {
+        let mut note_inner = vec![xml!(sup [] [xml!(n.to_string())])];
+        note_inner.extend(note);
+        Ok(xml!(span [class="sidenote-anchor"] [
+            xml!(sup [class="sidenote-ref"] [xml!(n.to_string())]),
+            xml!(small [class="sidenote"] note_inner)
+        ])
+        .nosnippet())
+    } else {
+        Ok(xml!(sup [class="footnote-ref", id=format!("fnref-{}", n)] [
+            xml!(a [href=format!("#fn-{}", n)] [xml!(n.to_string())])
+        ])
+        .nosnippet())
+    }
+}
+
+// 本文中の\footnoteを番号順に並べた注釈欄
+fn footnotes_section(ctx: &Context) -> Option<XMLElem> {
+    let footnotes = &ctx.page.borrow().footnotes;
+    if ctx.sidenote || footnotes.is_empty() {
+        return None;
+    }
+    let items = footnotes
+        .iter()
+        .enumerate()
+        .map(|(i, note)| {
+            let n = i + 1;
+            let mut inner = note.clone();
+            inner.push(xml!(a [href=format!("#fnref-{}", n), class="footnote-backref"] [xml!("↩".to_owned())]));
+            xml!(li [id=format!("fn-{}", n)] inner)
+        })
+        .collect::<Vec<_>>();
+    Some(xml!(section[class = "footnotes"][xml!(ol [] items)]).nosnippet())
+}
+
+fn execute_cite(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
+    let key = value_utils::get_str(&attrs, "key", &ctx.location)?;
+    let entry = ctx
+        .bibliography
+        .and_then(|bib| bib.get(key))
+        .ok_or_else(|| Error::InvalidAttribute {
+            name: "key".to_owned(),
+            reason: format!("unknown citation key \"{}\"", key),
+            loc: Box::new(ctx.location.clone()),
+        })?;
+    let n = {
+        let mut page = ctx.page.borrow_mut();
+        if let Some(i) = page.citations.iter().position(|cited| cited == key) {
+            i + 1
+        } else {
+            page.citations.push(key.to_owned());
+            page.citations.len()
+        }
+    };
+    let label = match ctx.citation_style {
+        CitationStyle::Numeric => format!("[{}]", n),
+        CitationStyle::AuthorYear => format!("({})", entry.author_year_label()),
+    };
+    Ok(xml!(a [class="citation", href=format!("#ref-{}", key)] [xml!(label)]).nosnippet())
+}
+
+fn format_reference(entry: &Entry) -> Vec<XMLElem> {
+    let mut inner = Vec::new();
+    let authors = entry.authors();
+    if !authors.is_empty() {
+        let authors = authors
+            .into_iter()
+            .map(Entry::display_name)
+            .collect::<Vec<_>>();
+        inner.push(xml!(format!("{}. ", authors.join(", "))));
+    }
+    if let Some(title) = entry.fields.get("title") {
+        inner.push(xml!(cite [] [xml!(title.to_owned())]));
+        inner.push(xml!(". ".to_owned()));
+    }
+    let container = [
+        "journal",
+        "booktitle",
+        "publisher",
+        "howpublished",
+        "organization",
+        "institution",
+    ]
+    .iter()
+    .find_map(|field| entry.fields.get(*field));
+    if let Some(container) = container {
+        inner.push(xml!(format!("{}, ", container)));
+    }
+    inner.push(xml!(format!("{}.", entry.year())));
+    let url = entry.fields.get("url").cloned().or_else(|| {
+        entry
+            .fields
+            .get("doi")
+            .map(|doi| format!("https://doi.org/{}", doi))
+    });
+    if let Some(url) = url.filter(|url| is_http_url(url)) {
+        inner.push(xml!(" ".to_owned()));
+        inner.push(xml!(a[href = url][xml!(url.clone())]));
+    }
+    inner
+}
+
+// 本文中で\citeされた文献の一覧。番号順なら引用順、著者年形式なら著者名順
+fn references_section(ctx: &Context) -> Option<XMLElem> {
+    let bib = ctx.bibliography?;
+    let page = ctx.page.borrow();
+    if page.citations.is_empty() {
+        return None;
+    }
+    let mut entries = page
+        .citations
+        .iter()
+        .filter_map(|key| bib.get(key))
+        .collect::<Vec<_>>();
+    if ctx.citation_style == CitationStyle::AuthorYear {
+        entries.sort_by_key(|entry| entry.author_year_label());
+    }
+    let items = entries
+        .into_iter()
+        .map(|entry| xml!(li [id=format!("ref-{}", entry.key), class="reference"] format_reference(entry)))
+        .collect::<Vec<_>>();
+    let list = match ctx.citation_style {
+        CitationStyle::Numeric => xml!(ol [] items),
+        CitationStyle::AuthorYear => xml!(ul [] items),
+    };
+    Some(xml!(section [class="references"] [
+        xml!(header [] [
+            XMLElem::WithElem(format!("h{}", ctx.level), vec![
+                xml::Attr::Pair("style".to_owned(), "font-weight: 500".to_owned())
+            ], vec![
+                xml!(span [class="sharp"] [XMLElem::Text("#".repeat(ctx.level))]),
+                xml!("参考文献".to_owned())
+            ])
+        ]),
+        list
+    ]))
+}
+
+// 図表・数式番号の表記。\refからも同じ表記で参照する
+pub fn number_label(cmd_name: &str, n: usize) -> String {
+    match cmd_name {
+        "table" => format!("表{}", n),
+        "equation" => format!("式({})", n),
+        "blockcode" => format!("リスト{}", n),
+        _ => format!("図{}", n),
+    }
+}
+
+// 番号を振るコマンドなら番号のカウンタを返す。図・表・ギャラリーは同じ番号を共有する
+pub fn numbering_counter(cmd: &Cmd) -> Option<&'static str> {
+    let labeled = cmd.attrs.contains_key("caption") || cmd.attrs.contains_key("id");
+    match cmd.name.as_str() {
+        "figure" => Some("figure"),
+        "table" | "gallery" if labeled => Some("figure"),
+        "blockcode" if labeled => Some("listing"),
+        "equation" => Some("equation"),
+        _ => None,
+    }
+}
+
+fn number(ctx: &Context) -> EResult<usize> {
+    ctx.numbering
+        .and_then(|numbering| numbering.numbers.get(&ctx.location))
+        .copied()
+        .ok_or_else(|| Error::Internal(format!("{} is not numbered", ctx.location)))
+}
+
+fn caption_number(ctx: &Context, cmd_name: &str) -> EResult<XMLElem> {
+    Ok(xml!(
+        span[class = "caption-number"][xml!(number_label(cmd_name, number(ctx)?))]
+    ))
+}
+
+struct TableCell {
+    inner: Vec<XMLElem>,
+    colspan: usize,
+    align: Option<String>,
+}
+
+fn verify_align(align: &str, loc: &Location) -> EResult<String> {
+    match align {
+        "left" | "center" | "right" => Ok(align.to_owned()),
+        _ => Err(Error::InvalidAttribute {
+            name: "align".to_owned(),
+            reason: "align must be \"left\", \"center\" or \"right\"".to_owned(),
+            loc: Box::new(loc.to_owned()),
+        }),
+    }
+}
+
+fn process_table_row(ctx: Context, row: Vec<TextElemAst>) -> EResult<Vec<TableCell>> {
+    row.into_iter()
+        .filter(|(e, _)| !matches!(e, TextElem::Plain(s) if s.trim().is_empty()))
+        .map(|(e, loc)| match e {
+            TextElem::Cmd(cmd) if cmd.name == "cell" => {
+                let colspan = value_utils::verify_int(&cmd.attrs, "colspan", &loc)?.unwrap_or(1);
+                if colspan < 1 {
+                    return Err(Error::InvalidAttribute {
+                        name: "colspan".to_owned(),
+                        reason: "colspan must be positive".to_owned(),
+                        loc: Box::new(loc),
+                    });
+                }
+                let align = value_utils::verify_str(&cmd.attrs, "align", &loc)?
+                    .map(|align| verify_align(align, &loc))
+                    .transpose()?;
+                Ok(TableCell {
+                    inner: process_text(ctx.fork_with_loc(loc), cmd.inner)?,
+                    colspan: colspan as usize,
+                    align,
+                })
+            }
+            _ => Err(Error::ProcessError {
+                loc,
+                desc: "table rows can only have \\cell as child element.".to_owned(),
+            }),
+        })
+        .collect()
+}
+
+fn render_table_row(tag: &str, row: Vec<TableCell>, aligns: &[String]) -> XMLElem {
+    let mut col = 0;
+    let cells = row
+        .into_iter()
+        .map(|cell| {
+            let mut attrs = Vec::new();
+            if cell.colspan > 1 {
+                attrs.push(xml::Attr::Pair(
+                    "colspan".to_owned(),
+                    cell.colspan.to_string(),
+                ));
+            }
+            if let Some(align) = cell.align.as_ref().or_else(|| aligns.get(col)) {
+                attrs.push(xml::Attr::Pair(
+                    "class".to_owned(),
+                    format!("align-{}", align),
+                ));
+            }
+            col += cell.colspan;
+            XMLElem::WithElem(tag.to_owned(), attrs, cell.inner)
+        })
+        .collect();
+    xml!(tr [] cells)
+}
+
+fn execute_table(
+    ctx: Context,
+    attrs: HashMap<String, ValueAst>,
+    inner: Vec<TextElemAst>,
+) -> EResult<XMLElem> {
+    let caption = value_utils::verify_text(&attrs, "caption", &ctx.location)?;
+    let id = value_utils::verify_str(&attrs, "id", &ctx.location)?;
+    let number = if caption.is_some() || id.is_some() {
+        Some(caption_number(&ctx, "table")?)
+    } else {
+        None
+    };
+    let aligns = value_utils::verify_list(&attrs, "align", &ctx.location, &crate::ValueType::Str)?
+        .unwrap_or(&[])
+        .iter()
+        .map(|(align, loc)| verify_align(align.str().unwrap(), loc))
+        .collect::<EResult<Vec<_>>>()?;
+    let text_cells = |row: &[String]| {
+        row.iter()
+            .map(|cell| TableCell {
+                inner: vec![xml!(cell.to_owned())],
+                colspan: 1,
+                align: None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut head = Vec::new();
+    let mut body = Vec::new();
+    if let Some(csv) = value_utils::verify_str(&attrs, "csv", &ctx.location)? {
+        if !inner.is_empty() {
+            return Err(Error::ProcessError {
+                loc: ctx.location,
+                desc: "\\table cannot have both csv and rows.".to_owned(),
+            });
+        }
+        let header_rows = value_utils::verify_int(&attrs, "header", &ctx.location)?.unwrap_or(1);
+        let csv_path = PathBuf::from(absolute(&ctx.location, csv, ctx.path)?);
+        let records = ctx
+            .csv_tables
+            .get(&csv_path)
+            .ok_or_else(|| Error::InvalidLink {
+                link: csv_path.clone(),
+                msg: "csv file not found".to_owned(),
+                loc: Box::new(ctx.location.clone()),
+            })?;
+        for (i, record) in records.iter().enumerate() {
+            if (i as i64) < header_rows {
+                head.push(text_cells(record));
+            } else {
+                body.push(text_cells(record));
+            }
+        }
+    } else {
+        let mut width = None;
+        for (e, loc) in inner {
+            let (is_head, row) = match e {
+                TextElem::Plain(s) if s.trim().is_empty() => continue,
+                TextElem::Cmd(cmd) if cmd.name == "thead" || cmd.name == "row" => (
+                    cmd.name == "thead",
+                    process_table_row(ctx.fork_with_loc(loc.clone()), cmd.inner)?,
+                ),
+                _ => {
+                    return Err(Error::ProcessError {
+                        loc,
+                        desc: "\\table can only have \\thead and \\row as child element."
+                            .to_owned(),
+                    })
+                }
+            };
+            let row_width = row.iter().map(|cell| cell.colspan).sum::<usize>();
+            match width {
+                Some(width) if width != row_width => {
+                    return Err(Error::ProcessError {
+                        loc,
+                        desc: format!("row has {} columns but the table has {}", row_width, width),
+                    })
+                }
+                _ => width = Some(row_width),
+            }
+            if is_head {
+                head.push(row);
+            } else {
+                body.push(row);
+            }
+        }
+    }
+    let mut table = Vec::new();
+    // idだけの表にも\refで参照できるように番号のキャプションを付ける
+    if let Some(number) = number {
+        let mut caption_xml = vec![number];
+        if let Some(caption) = caption {
+            caption_xml.append(&mut process_text(ctx.clone(), caption.to_vec())?);
+        }
+        table.push(xml!(caption [] caption_xml));
+    }
+    if !head.is_empty() {
+        table.push(xml!(thead [] head.into_iter().map(|row| render_table_row("th", row, &aligns)).collect()));
+    }
+    table.push(
+        xml!(tbody [] body.into_iter().map(|row| render_table_row("td", row, &aligns)).collect()),
+    );
+    let table = if let Some(id) = id {
+        xml!(table [id=id] table)
+    } else {
+        xml!(table [] table)
+    };
+    Ok(xml!(div[class = "table-container"][table]))
+}
+
+fn execute_math(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
+    let src = value_utils::get_str(&attrs, "src", &ctx.location)?;
+    let loc = attrs
+        .get("src")
+        .map(|(_, loc)| loc.to_owned())
+        .unwrap_or(ctx.location);
+    Ok(math::to_html(src, false, &loc)?.nosnippet())
+}
+
+fn execute_equation(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
+    let src = value_utils::get_str(&attrs, "src", &ctx.location)?;
+    let id = value_utils::verify_str(&attrs, "id", &ctx.location)?;
+    let loc = attrs
+        .get("src")
+        .map(|(_, loc)| loc.to_owned())
+        .unwrap_or_else(|| ctx.location.clone());
+    let n = number(&ctx)?;
+    let inner = vec![
+        math::to_html(src, true, &loc)?,
+        xml!(span[class = "equation-number"][xml!(format!("({})", n))]),
+    ];
+    if let Some(id) = id {
+        Ok(xml!(div [class="equation", id=id] inner).nosnippet())
+    } else {
+        Ok(xml!(div [class="equation"] inner).nosnippet())
+    }
+}
+
+fn execute_ref(ctx: Context, attrs: HashMap<String, ValueAst>) -> EResult<XMLElem> {
+    let id = value_utils::get_str(&attrs, "id", &ctx.location)?;
+    let label = ctx
+        .numbering
+        .and_then(|numbering| numbering.labels.get(id))
+        .ok_or_else(|| Error::InvalidAttribute {
+            name: "id".to_owned(),
+            reason: format!("no numbered element has id \"{}\"", id),
+            loc: Box::new(ctx.location.clone()),
+        })?;
+    Ok(xml!(a [class="ref", href=format!("#{}", id)] [xml!(label.to_owned())]))
+}
+
 fn execute_categories(ctx: Context) -> EResult<XMLElem> {
     let cateories = ctx
         .categories
@@ -818,7 +3153,28 @@ fn process_text(ctx: Context, textelems: Vec<TextElemAst>) -> EResult<Vec<XMLEle
         .collect::<EResult<Vec<_>>>()
 }
 
+// 出力の根にこのコマンドの位置を示すCMD_MARKERを付ける。子のコマンドが付けた印があればそちらを残す
 fn process_cmd(ctx: Context, cmd: Cmd) -> EResult<XMLElem> {
+    let loc = ctx.location.clone();
+    let page = ctx.page.clone();
+    let mut elem = dispatch_cmd(ctx, cmd)?;
+    if let XMLElem::Single(_, attrs) | XMLElem::WithElem(_, attrs, _) = &mut elem {
+        if !attrs
+            .iter()
+            .any(|attr| matches!(attr, xml::Attr::Pair(name, _) if name == CMD_MARKER))
+        {
+            let mut page = page.borrow_mut();
+            attrs.push(xml::Attr::Pair(
+                CMD_MARKER.to_owned(),
+                page.cmd_locations.len().to_string(),
+            ));
+            page.cmd_locations.push(loc);
+        }
+    }
+    Ok(elem)
+}
+
+fn dispatch_cmd(ctx: Context, cmd: Cmd) -> EResult<XMLElem> {
     match cmd.name.as_str() {
         "center" => execute_center(ctx, cmd.attrs, cmd.inner),
         "profile" => execute_profile(ctx, cmd.attrs, cmd.inner),
@@ -830,13 +3186,39 @@ fn process_cmd(ctx: Context, cmd: Cmd) -> EResult<XMLElem> {
         "img" => execute_img(ctx, cmd.attrs),
         "p" => execute_p(ctx, cmd.inner),
         "address" => execute_address(ctx, cmd.inner),
+        "admonition" => execute_admonition(ctx, cmd.attrs, cmd.inner),
+        "details" => execute_details(ctx, cmd.attrs, cmd.inner),
+        "quote" => execute_quote(ctx, cmd.attrs, cmd.inner),
+        "q" => execute_q(ctx, cmd.attrs, cmd.inner),
         "ul" => execute_ul(ctx, cmd.inner),
+        "ol" => execute_ol(ctx, cmd.attrs, cmd.inner),
+        "dl" => execute_dl(ctx, cmd.inner),
         "link" => execute_link(ctx, cmd.attrs, cmd.inner),
         "n" => execute_n(ctx, cmd.inner),
         "line" => execute_line(ctx, cmd.inner),
+        "em" => execute_inline(ctx, "em", cmd.inner),
+        "strong" => execute_inline(ctx, "strong", cmd.inner),
+        "del" => execute_inline(ctx, "del", cmd.inner),
+        "sub" => execute_inline(ctx, "sub", cmd.inner),
+        "sup" => execute_inline(ctx, "sup", cmd.inner),
+        "kbd" => execute_inline(ctx, "kbd", cmd.inner),
+        "abbr" => execute_abbr(ctx, cmd.attrs, cmd.inner),
+        "ruby" => execute_ruby(ctx, cmd.attrs, cmd.inner),
         "blockcode" => execute_blockcode(ctx, cmd.attrs),
         "iframe" => execute_iframe(ctx, cmd.attrs),
+        "video" => execute_video(ctx, cmd.attrs),
+        "audio" => execute_audio(ctx, cmd.attrs),
+        "youtube" => execute_youtube(ctx, cmd.attrs),
+        "twitter" => execute_twitter(ctx, cmd.attrs),
+        "gist" => execute_gist(ctx, cmd.attrs),
         "figure" => execute_figure(ctx, cmd.attrs, cmd.inner),
+        "gallery" => execute_gallery(ctx, cmd.attrs, cmd.inner),
+        "footnote" => execute_footnote(ctx, cmd.inner),
+        "cite" => execute_cite(ctx, cmd.attrs),
+        "table" => execute_table(ctx, cmd.attrs, cmd.inner),
+        "math" => execute_math(ctx, cmd.attrs),
+        "equation" => execute_equation(ctx, cmd.attrs),
+        "ref" => execute_ref(ctx, cmd.attrs),
         _ => Err(Error::NoSuchCmd {
             loc: ctx.location,
             name: cmd.name.to_owned(),
@@ -858,10 +3240,7 @@ pub fn generate_category_pages(
                 .iter()
                 .map(|(p, title)| {
                     println!("{:?}", p);
-                    let title_xml = title
-                        .iter()
-                        .map(|(e, _)| process_text_elem(report.general_context(p), e.to_owned()))
-                        .collect::<EResult<Vec<XMLElem>>>()?;
+                    let title_xml = process_title(&report.general_context(p), title)?;
                     let path = resolve(p.to_str().unwrap(), &output_path)?;
                     Ok(xml!(li [] [xml!(a [href=path.to_str().unwrap().to_owned()] title_xml)]))
                 })
@@ -877,9 +3256,30 @@ pub fn generate_category_pages(
                 title,
                 &format!("categori/{}.html,", category_name),
                 css,
+                None,
             )?;
             // TODO: add og:type metatag
-            Ok((output_path, Html::new("html", html(body, header))))
+            let mut html = html(body, header);
+            let mut found = Vec::new();
+            check_amp(
+                &mut html,
+                &[],
+                &Location::Generated,
+                Some(false),
+                &mut found,
+            );
+            let violations = found
+                .into_iter()
+                .map(|(loc, msg)| (loc, format!("{:?}: {}", output_path, msg)))
+                .collect();
+            pages(
+                &report.config,
+                &output_path,
+                &Location::Generated,
+                html,
+                violations,
+            )
         })
-        .collect::<EResult<Vec<(PathBuf, Html)>>>()
+        .collect::<EResult<Vec<_>>>()
+        .map(|pages| pages.into_iter().flatten().collect())
 }
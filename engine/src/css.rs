@@ -0,0 +1,351 @@
+use super::xml::{Attr, XMLElem};
+use std::collections::HashSet;
+
+// CSSを規則単位に分けたもの。宣言の中身は解釈しない
+#[derive(Debug, PartialEq)]
+pub enum Item {
+    Rule {
+        selectors: Vec<String>,
+        body: String,
+    },
+    // 中に規則を持つ@mediaや@supports
+    Block {
+        prelude: String,
+        items: Vec<Item>,
+    },
+    // @keyframesや@font-faceなど、そのまま残すもの
+    Raw(String),
+}
+
+const NESTING_AT_RULES: [&str; 3] = ["@media", "@supports", "@document"];
+
+// AMPのランタイムが要素の中に生成する要素
+const RUNTIME_ELEMENTS: [(&str, &str); 4] = [
+    ("amp-img", "img"),
+    ("amp-video", "video"),
+    ("amp-audio", "audio"),
+    ("amp-iframe", "iframe"),
+];
+
+// コメントを除き、文字列と括弧の中を飛ばしながらdelimsのどれかが現れる位置を探す
+fn find_top_level(src: &str, delims: &[char]) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut chars = src.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if let Some(q) = quote {
+            if c == '\\' {
+                chars.next();
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '/' if src[i..].starts_with("/*") => {
+                let end = src[i + 2..]
+                    .find("*/")
+                    .map(|e| i + 2 + e + 2)
+                    .unwrap_or(src.len());
+                while chars.peek().map(|(j, _)| *j < end).unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '{' if depth == 0 && delims.contains(&'{') => return Some(i),
+            '{' => depth += 1,
+            '}' if depth == 0 && delims.contains(&'}') => return Some(i),
+            '}' => depth -= 1,
+            _ if depth == 0 && delims.contains(&c) => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+fn strip_comments(src: &str) -> String {
+    let mut out = String::new();
+    let mut rest = src;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = rest[start + 2..]
+            .find("*/")
+            .map(|end| &rest[start + 2 + end + 2..])
+            .unwrap_or("");
+    }
+    out.push_str(rest);
+    out
+}
+
+fn split_selectors(prelude: &str) -> Vec<String> {
+    let mut selectors = Vec::new();
+    let mut rest = prelude;
+    while let Some(i) = find_top_level(rest, &[',']) {
+        selectors.push(rest[..i].trim().to_owned());
+        rest = &rest[i + 1..];
+    }
+    selectors.push(rest.trim().to_owned());
+    selectors.into_iter().filter(|s| !s.is_empty()).collect()
+}
+
+pub fn parse(src: &str) -> Vec<Item> {
+    let mut items = Vec::new();
+    let mut rest = src;
+    while let Some(start) = find_top_level(rest, &['{', ';', '}']) {
+        let prelude = strip_comments(&rest[..start]).trim().to_owned();
+        if !rest[start..].starts_with('{') {
+            // @importなど。閉じ括弧の余りは捨てる
+            if !prelude.is_empty() {
+                items.push(Item::Raw(prelude + ";"));
+            }
+            rest = &rest[start + 1..];
+            continue;
+        }
+        let body_start = start + 1;
+        let body_len =
+            find_top_level(&rest[body_start..], &['}']).unwrap_or(rest.len() - body_start);
+        let body = &rest[body_start..body_start + body_len];
+        if NESTING_AT_RULES
+            .iter()
+            .any(|at| prelude.to_lowercase().starts_with(at))
+        {
+            items.push(Item::Block {
+                prelude,
+                items: parse(body),
+            });
+        } else if prelude.starts_with('@') {
+            items.push(Item::Raw(format!("{}{{{}}}", prelude, body.trim())));
+        } else {
+            items.push(Item::Rule {
+                selectors: split_selectors(&prelude),
+                body: strip_comments(body).trim().to_owned(),
+            });
+        }
+        rest = &rest[(body_start + body_len + 1).min(rest.len())..];
+    }
+    items
+}
+
+pub fn serialize(items: &[Item]) -> String {
+    items
+        .iter()
+        .map(|item| match item {
+            Item::Rule { selectors, body } => format!("{}{{{}}}", selectors.join(","), body),
+            Item::Block { prelude, items } => format!("{}{{{}}}", prelude, serialize(items)),
+            Item::Raw(raw) => raw.to_owned(),
+        })
+        .collect()
+}
+
+// ページ中で使われている要素名、クラス、ID
+#[derive(Default, Debug)]
+pub struct Usage {
+    elements: HashSet<String>,
+    classes: HashSet<String>,
+    ids: HashSet<String>,
+}
+
+impl Usage {
+    fn add(&mut self, name: &str, attrs: &[Attr]) {
+        let name = name.to_lowercase();
+        if let Some((_, generated)) = RUNTIME_ELEMENTS.iter().find(|(amp, _)| *amp == name) {
+            self.elements.insert((*generated).to_owned());
+        }
+        self.elements.insert(name);
+        for attr in attrs {
+            match attr {
+                Attr::Pair(name, value) if name == "class" => self
+                    .classes
+                    .extend(value.split_whitespace().map(str::to_owned)),
+                Attr::Pair(name, value) if name == "id" => {
+                    self.ids.insert(value.to_owned());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // ハイライトしたコードなど、文字列のまま埋め込んだHTMLも調べる
+    fn add_raw(&mut self, raw: &str, matcher: &RawMatcher) {
+        for cap in matcher.tag.captures_iter(raw) {
+            self.elements.insert(cap[1].to_lowercase());
+        }
+        for cap in matcher.attr.captures_iter(raw) {
+            let values = cap[2].split_whitespace().map(str::to_owned);
+            if &cap[1] == "class" {
+                self.classes.extend(values);
+            } else {
+                self.ids.extend(values);
+            }
+        }
+    }
+
+    pub fn collect(elem: &XMLElem) -> Self {
+        let matcher = RawMatcher {
+            tag: regex::Regex::new(r"<([A-Za-z][A-Za-z0-9-]*)").unwrap(),
+            attr: regex::Regex::new(r#"\b(class|id)="([^"]*)""#).unwrap(),
+        };
+        let mut usage = Self::default();
+        usage.collect_impl(elem, &matcher);
+        usage
+    }
+
+    fn collect_impl(&mut self, elem: &XMLElem, matcher: &RawMatcher) {
+        match elem {
+            XMLElem::Single(name, attrs) => self.add(name, attrs),
+            XMLElem::WithElem(name, attrs, inner) => {
+                self.add(name, attrs);
+                for child in inner {
+                    self.collect_impl(child, matcher);
+                }
+            }
+            XMLElem::Raw(raw) => self.add_raw(raw, matcher),
+            XMLElem::Text(_) => (),
+        }
+    }
+
+    // 擬似クラスや属性、結合子は無視し、現れる名前が全て使われていれば当てはまり得るとみなす
+    fn may_match(&self, selector: &str, matcher: &Matcher) -> bool {
+        // エスケープを含むものは解釈せず残す
+        if selector.contains('\\') {
+            return true;
+        }
+        let selector = matcher.pseudo.replace_all(selector, "");
+        matcher
+            .name
+            .captures_iter(&selector)
+            .all(|cap| match &cap[1] {
+                "." => self.classes.contains(&cap[2]),
+                "#" => self.ids.contains(&cap[2]),
+                _ => self.elements.contains(&cap[2].to_lowercase()),
+            })
+    }
+}
+
+struct Matcher {
+    pseudo: regex::Regex,
+    name: regex::Regex,
+}
+
+struct RawMatcher {
+    tag: regex::Regex,
+    attr: regex::Regex,
+}
+
+// 使われていない規則を除く。中身が無くなった@mediaなども除く
+pub fn prune(items: &[Item], usage: &Usage) -> Vec<Item> {
+    let matcher = Matcher {
+        pseudo: regex::Regex::new(r"::?[A-Za-z-]+(\([^()]*\))?|\[[^\]]*\]").unwrap(),
+        name: regex::Regex::new(r"([.#]?)([A-Za-z_-][A-Za-z0-9_-]*)").unwrap(),
+    };
+    prune_impl(items, usage, &matcher)
+}
+
+fn prune_impl(items: &[Item], usage: &Usage, matcher: &Matcher) -> Vec<Item> {
+    items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Rule { selectors, body } => {
+                let selectors = selectors
+                    .iter()
+                    .filter(|selector| usage.may_match(selector, matcher))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if selectors.is_empty() {
+                    None
+                } else {
+                    Some(Item::Rule {
+                        selectors,
+                        body: body.to_owned(),
+                    })
+                }
+            }
+            Item::Block { prelude, items } => {
+                let items = prune_impl(items, usage, matcher);
+                if items.is_empty() {
+                    None
+                } else {
+                    Some(Item::Block {
+                        prelude: prelude.to_owned(),
+                        items,
+                    })
+                }
+            }
+            Item::Raw(raw) => Some(Item::Raw(raw.to_owned())),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let css = r#"
+/* comment */
+p, .a > b { color: red; }
+.x::before { content: "}"; }
+@media (max-width: 10px) { div.y { margin: 0 } }
+@keyframes k { from { opacity: 0 } to { opacity: 1 } }
+"#;
+        let items = parse(css);
+        assert_eq!(items.len(), 4);
+        assert_eq!(
+            items[0],
+            Item::Rule {
+                selectors: vec!["p".to_owned(), ".a > b".to_owned()],
+                body: "color: red;".to_owned()
+            }
+        );
+        assert_eq!(
+            serialize(&items),
+            concat!(
+                "p,.a > b{color: red;}",
+                ".x::before{content: \"}\";}",
+                "@media (max-width: 10px){div.y{margin: 0}}",
+                "@keyframes k{from { opacity: 0 } to { opacity: 1 }}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_prune() {
+        let css = concat!(
+            "p,table{margin:0}",
+            ".hl-code{color:#333}",
+            ".img-placeholder-blur img{filter:blur(8px)}",
+            "section[expanded]>.details-summary::before{transform:rotate(90deg)}",
+            "@media screen{#root{width:55%}pre{padding:1vh}}",
+        );
+        let page = XMLElem::WithElem(
+            "div".to_owned(),
+            vec![Attr::Pair("id".to_owned(), "root".to_owned())],
+            vec![
+                XMLElem::WithElem(
+                    "amp-img".to_owned(),
+                    vec![Attr::Pair(
+                        "class".to_owned(),
+                        "img-placeholder img-placeholder-blur".to_owned(),
+                    )],
+                    vec![],
+                ),
+                XMLElem::WithElem("p".to_owned(), vec![], vec![]),
+            ],
+        );
+        let usage = Usage::collect(&page);
+        assert_eq!(
+            serialize(&prune(&parse(css), &usage)),
+            "p{margin:0}.img-placeholder-blur img{filter:blur(8px)}@media screen{#root{width:55%}}"
+        );
+        let usage = Usage::collect(&XMLElem::Raw(
+            "<span class=\"hl-code line\">x</span>".to_owned(),
+        ));
+        assert_eq!(
+            serialize(&prune(&parse(css), &usage)),
+            ".hl-code{color:#333}"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use super::{Error, Location, Position};
+
+pub type Record = (Vec<String>, Location);
+
+// RFC 4180相当。各レコードにはその開始行の位置を付ける。空行は読み飛ばす
+pub fn parse(fname: &str, src: &str) -> Result<Vec<Record>, Error> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = src.chars().peekable();
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut in_quote = false;
+    let mut quote_start = 1;
+    // 現在のレコードに""で囲んだフィールドがあるか
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        if in_quote {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quote = false,
+                '\n' => {
+                    line += 1;
+                    field.push(c);
+                }
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => {
+                in_quote = true;
+                quoted = true;
+                quote_start = line;
+            }
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\n' if record.is_empty() && field.is_empty() && !quoted => {
+                line += 1;
+                record_line = line;
+            }
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push((
+                    std::mem::take(&mut record),
+                    Location::At(Position::new(fname, record_line, 1)),
+                ));
+                quoted = false;
+                line += 1;
+                record_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quote {
+        return Err(Error::SyntaxError(Location::At(Position::new(
+            fname,
+            quote_start,
+            1,
+        ))));
+    }
+    if !field.is_empty() || !record.is_empty() || quoted {
+        record.push(field);
+        records.push((record, Location::At(Position::new(fname, record_line, 1))));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let records = parse(
+            "a.csv",
+            "name,value\r\n\"a,b\",\"say \"\"hi\"\"\"\n\"multi\nline\",3\n",
+        )
+        .unwrap();
+        assert_eq!(
+            records
+                .iter()
+                .map(|(r, _)| r.clone())
+                .collect::<Vec<Vec<String>>>(),
+            vec![
+                vec!["name".to_owned(), "value".to_owned()],
+                vec!["a,b".to_owned(), "say \"hi\"".to_owned()],
+                vec!["multi\nline".to_owned(), "3".to_owned()],
+            ]
+        );
+        assert_eq!(records[2].1, Location::At(Position::new("a.csv", 3, 1)));
+        assert_eq!(parse("a.csv", "a,b").unwrap().len(), 1);
+        // 空行と末尾の改行はレコードにしない
+        let records = parse("a.csv", "a,b\n\n\r\nc,d\n\n").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].1, Location::At(Position::new("a.csv", 4, 1)));
+        assert_eq!(parse("a.csv", "\"\"\n").unwrap()[0].0, vec![String::new()]);
+        assert!(parse("a.csv", "a,\"b\n").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Op {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+// 最長共通部分列から編集列を作る
+fn edit_script(before: &[&str], after: &[&str]) -> Vec<Op> {
+    let (n, m) = (before.len(), after.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if before[i] == after[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && before[i] == after[j] {
+            ops.push(Op::Equal(i, j));
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            ops.push(Op::Insert(j));
+            j += 1;
+        } else {
+            ops.push(Op::Delete(i));
+            i += 1;
+        }
+    }
+    // 同じ位置の削除は追加より先に出す
+    let mut idx = 0;
+    while idx < ops.len() {
+        let end = ops[idx..]
+            .iter()
+            .position(|op| matches!(op, Op::Equal(_, _)))
+            .map(|p| idx + p)
+            .unwrap_or(ops.len());
+        ops[idx..end].sort_by_key(|op| match op {
+            Op::Delete(_) => 0,
+            _ => 1,
+        });
+        idx = end + 1;
+    }
+    ops
+}
+
+// "@@ -a,b +c,d @@"の開始行。空の範囲は直前の行を指す
+fn hunk_range(start: usize, len: usize) -> String {
+    if len == 0 {
+        format!("{},0", start)
+    } else {
+        format!("{},{}", start + 1, len)
+    }
+}
+
+// 前後context行を含むunified diffの行(先頭に" ", "+", "-"または"@@"を持つ)を返す。
+// 差分が無い場合は全ての行を変更なしとして返す
+pub fn unified(before: &str, after: &str, context: usize) -> Vec<String> {
+    let before = before.lines().collect::<Vec<_>>();
+    let after = after.lines().collect::<Vec<_>>();
+    let ops = edit_script(&before, &after);
+    let changes = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, Op::Equal(_, _)))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return before.iter().map(|line| format!(" {}", line)).collect();
+    }
+    // 近い変更をまとめてハンクにする
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for idx in changes {
+        let from = idx.saturating_sub(context);
+        let to = (idx + context + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some((_, end)) if from <= *end => *end = to,
+            _ => hunks.push((from, to)),
+        }
+    }
+    let mut lines = Vec::new();
+    for (from, to) in hunks {
+        let ops = &ops[from..to];
+        // ハンク開始位置までに消費した行数
+        let (before_start, after_start) = match ops[0] {
+            Op::Equal(i, j) => (i, j),
+            Op::Delete(i) => (
+                i,
+                ops.iter()
+                    .find_map(|op| match op {
+                        Op::Equal(_, j) | Op::Insert(j) => Some(*j),
+                        _ => None,
+                    })
+                    .unwrap_or(after.len()),
+            ),
+            Op::Insert(j) => (
+                ops.iter()
+                    .find_map(|op| match op {
+                        Op::Equal(i, _) | Op::Delete(i) => Some(*i),
+                        _ => None,
+                    })
+                    .unwrap_or(before.len()),
+                j,
+            ),
+        };
+        let before_len = ops.iter().filter(|op| !matches!(op, Op::Insert(_))).count();
+        let after_len = ops.iter().filter(|op| !matches!(op, Op::Delete(_))).count();
+        lines.push(format!(
+            "@@ -{} +{} @@",
+            hunk_range(before_start, before_len),
+            hunk_range(after_start, after_len)
+        ));
+        for op in ops {
+            lines.push(match op {
+                Op::Equal(i, _) => format!(" {}", before[*i]),
+                Op::Delete(i) => format!("-{}", before[*i]),
+                Op::Insert(j) => format!("+{}", after[*j]),
+            });
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unified() {
+        let before = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj";
+        let after = "a\nb\nC\nd\ne\nf\ng\nh\ni\nj\nk";
+        assert_eq!(
+            unified(before, after, 1),
+            vec![
+                "@@ -2,3 +2,3 @@",
+                " b",
+                "-c",
+                "+C",
+                " d",
+                "@@ -10,1 +10,2 @@",
+                " j",
+                "+k",
+            ]
+        );
+        assert_eq!(
+            unified(before, after, 4)
+                .iter()
+                .filter(|l| l.starts_with("@@"))
+                .count(),
+            1
+        );
+        assert_eq!(unified("x", "", 3), vec!["@@ -1,1 +0,0 @@", "-x"]);
+        assert_eq!(unified("", "x", 3), vec!["@@ -0,0 +1,1 @@", "+x"]);
+        assert_eq!(unified("a\nb", "a\nb", 3), vec![" a", " b"]);
+    }
+}
@@ -0,0 +1,454 @@
+use super::xml::{Attr, XMLElem};
+use std::path::{Path, PathBuf};
+
+// HTML5のページから参照するスタイルシート。Report.cssをそのまま書き出す
+pub const STYLESHEET: &str = "style.css";
+
+// AMPのランタイムが解釈するだけで、HTML5では意味を持たない属性
+const AMP_ATTRS: [&str; 8] = [
+    "layout",
+    "placeholder",
+    "fallback",
+    "lightbox",
+    "object-fit",
+    "disable-session-states",
+    "noloading",
+    "resizable",
+];
+
+// 両方を出力する場合のAMP版のパス。articles/foo.html -> articles/foo.amp.html
+pub fn amp_path(path: &Path) -> PathBuf {
+    path.with_extension("amp.html")
+}
+
+fn get<'a>(attrs: &'a [Attr], name: &str) -> Option<&'a str> {
+    attrs.iter().find_map(|attr| match attr {
+        Attr::Pair(n, v) if n == name => Some(v.as_str()),
+        Attr::Single(n) if n == name => Some(""),
+        _ => None,
+    })
+}
+
+fn pair(name: &str, value: &str) -> Attr {
+    Attr::Pair(name.to_owned(), value.to_owned())
+}
+
+fn html_attrs(attrs: &[Attr]) -> Vec<Attr> {
+    attrs
+        .iter()
+        .filter(|attr| match attr {
+            Attr::Pair(name, _) | Attr::Single(name) => !AMP_ATTRS.contains(&name.as_str()),
+        })
+        .cloned()
+        .collect()
+}
+
+fn push_style(attrs: &mut Vec<Attr>, style: &str) {
+    for attr in attrs.iter_mut() {
+        if let Attr::Pair(name, value) = attr {
+            if name == "style" {
+                if !value.is_empty() {
+                    value.push_str("; ");
+                }
+                value.push_str(style);
+                return;
+            }
+        }
+    }
+    attrs.push(pair("style", style));
+}
+
+// AMPのlayoutを同じ見た目になるCSSに置き換える。
+// iframeは縦横比を持たないのでaspect-ratioで高さを決める
+fn layout_style(attrs: &[Attr]) -> Vec<String> {
+    let mut style = match get(attrs, "layout") {
+        Some("responsive") => {
+            let mut style = vec!["width: 100%".to_owned(), "height: auto".to_owned()];
+            if let (Some(w), Some(h)) = (get(attrs, "width"), get(attrs, "height")) {
+                style.push(format!("aspect-ratio: {} / {}", w, h));
+            }
+            style
+        }
+        Some("fill") => vec!["width: 100%".to_owned(), "height: 100%".to_owned()],
+        Some("fixed-height") => vec!["width: 100%".to_owned()],
+        _ => vec![],
+    };
+    if get(attrs, "object-fit") == Some("cover") {
+        style.push("object-fit: cover".to_owned());
+    }
+    style
+}
+
+fn with_layout(attrs: &[Attr]) -> Vec<Attr> {
+    let mut converted = html_attrs(attrs);
+    let style = layout_style(attrs);
+    if !style.is_empty() {
+        push_style(&mut converted, &style.join("; "));
+    }
+    converted
+}
+
+// プレースホルダは読み込み中に見える背景にする
+fn img(attrs: &[Attr], inner: &[XMLElem]) -> XMLElem {
+    let mut img_attrs = with_layout(attrs);
+    img_attrs.push(pair("loading", "lazy"));
+    img_attrs.push(pair("decoding", "async"));
+    for child in inner {
+        match child {
+            XMLElem::WithElem(name, attrs, _) if get(attrs, "placeholder").is_some() => {
+                if name == "amp-img" {
+                    if let Some(src) = get(attrs, "src") {
+                        push_style(
+                            &mut img_attrs,
+                            &format!("background: url('{}') center / cover", src),
+                        );
+                    }
+                } else if let Some(style) = get(attrs, "style") {
+                    push_style(&mut img_attrs, style);
+                }
+            }
+            _ => (),
+        }
+    }
+    let img = XMLElem::Single("img".to_owned(), img_attrs);
+    // amp-lightbox-galleryの代わりに原寸の画像へリンクする
+    match get(attrs, "src") {
+        Some(src) if get(attrs, "lightbox").is_some() => {
+            XMLElem::WithElem("a".to_owned(), vec![pair("href", src)], vec![img])
+        }
+        _ => img,
+    }
+}
+
+fn iframe(mut attrs: Vec<Attr>) -> XMLElem {
+    attrs.push(pair("loading", "lazy"));
+    XMLElem::WithElem("iframe".to_owned(), attrs, Vec::new())
+}
+
+fn convert_children(inner: &[XMLElem]) -> Vec<XMLElem> {
+    inner
+        .iter()
+        .filter(|child| match child {
+            XMLElem::Single(_, attrs) | XMLElem::WithElem(_, attrs, _) => {
+                get(attrs, "placeholder").is_none()
+            }
+            _ => true,
+        })
+        .map(convert)
+        .collect()
+}
+
+fn convert(elem: &XMLElem) -> XMLElem {
+    let (name, attrs, inner) = match elem {
+        XMLElem::Single(name, attrs) => (name.as_str(), attrs.as_slice(), &[][..]),
+        XMLElem::WithElem(name, attrs, inner) => {
+            (name.as_str(), attrs.as_slice(), inner.as_slice())
+        }
+        _ => return elem.clone(),
+    };
+    match name {
+        "amp-img" => img(attrs, inner),
+        "amp-video" => XMLElem::WithElem(
+            "video".to_owned(),
+            with_layout(attrs),
+            convert_children(inner),
+        ),
+        "amp-audio" => {
+            let mut audio_attrs = html_attrs(attrs)
+                .into_iter()
+                .filter(|attr| !matches!(attr, Attr::Pair(name, _) if name == "width" || name == "height"))
+                .collect::<Vec<_>>();
+            push_style(&mut audio_attrs, "width: 100%");
+            XMLElem::WithElem("audio".to_owned(), audio_attrs, convert_children(inner))
+        }
+        "amp-iframe" => iframe(with_layout(attrs)),
+        "amp-youtube" => {
+            let id = get(attrs, "data-videoid").unwrap_or_default();
+            let mut youtube_attrs = with_layout(attrs)
+                .into_iter()
+                .filter(|attr| !matches!(attr, Attr::Pair(name, _) if name == "data-videoid"))
+                .collect::<Vec<_>>();
+            youtube_attrs.push(pair(
+                "src",
+                &format!("https://www.youtube.com/embed/{}", id),
+            ));
+            youtube_attrs.push(Attr::Single("allowfullscreen".to_owned()));
+            iframe(youtube_attrs)
+        }
+        // ウィジェットのスクリプトは読み込まず、ツイートへのリンクだけを残す
+        "amp-twitter" => {
+            let link = inner
+                .iter()
+                .find_map(|child| match child {
+                    XMLElem::WithElem(_, _, link) => Some(link.clone()),
+                    _ => None,
+                })
+                .unwrap_or_default();
+            XMLElem::WithElem(
+                "blockquote".to_owned(),
+                vec![pair("class", "twitter-tweet")],
+                link,
+            )
+        }
+        "amp-gist" => {
+            let url = format!(
+                "https://gist.github.com/{}",
+                get(attrs, "data-gistid").unwrap_or_default()
+            );
+            XMLElem::WithElem(
+                "p".to_owned(),
+                vec![pair("class", "gist")],
+                vec![XMLElem::WithElem(
+                    "a".to_owned(),
+                    vec![pair("href", &url)],
+                    vec![XMLElem::Text(url.clone())],
+                )],
+            )
+        }
+        // amp-accordion > section > header + div -> details > summary + div
+        "amp-accordion" => {
+            let mut details = Vec::new();
+            for section in inner {
+                if let XMLElem::WithElem(_, section_attrs, section_inner) = section {
+                    let mut details_attrs = html_attrs(attrs);
+                    if get(section_attrs, "expanded").is_some() {
+                        details_attrs.push(Attr::Single("open".to_owned()));
+                    }
+                    let inner = section_inner
+                        .iter()
+                        .map(|child| match child {
+                            XMLElem::WithElem(name, attrs, inner) if name == "header" => {
+                                XMLElem::WithElem(
+                                    "summary".to_owned(),
+                                    attrs.clone(),
+                                    convert_children(inner),
+                                )
+                            }
+                            _ => convert(child),
+                        })
+                        .collect();
+                    details.push(XMLElem::WithElem(
+                        "details".to_owned(),
+                        details_attrs,
+                        inner,
+                    ));
+                }
+            }
+            if details.len() == 1 {
+                details.pop().unwrap()
+            } else {
+                XMLElem::WithElem("div".to_owned(), Vec::new(), details)
+            }
+        }
+        _ => match elem {
+            XMLElem::Single(name, _) => XMLElem::Single(name.to_owned(), html_attrs(attrs)),
+            _ => XMLElem::WithElem(name.to_owned(), html_attrs(attrs), convert_children(inner)),
+        },
+    }
+}
+
+fn is_amp_head_elem(elem: &XMLElem) -> bool {
+    match elem {
+        XMLElem::WithElem(name, attrs, inner) => match name.as_str() {
+            "script" => get(attrs, "src")
+                .map(|src| src.starts_with("https://cdn.ampproject.org/"))
+                .unwrap_or(false),
+            "style" => get(attrs, "amp-boilerplate").is_some(),
+            "noscript" => inner.iter().any(is_amp_head_elem),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn convert_head(head: &[XMLElem], stylesheet: &str, amphtml: Option<&str>) -> Vec<XMLElem> {
+    let mut converted = head
+        .iter()
+        .filter(|elem| !is_amp_head_elem(elem))
+        .map(|elem| match elem {
+            XMLElem::WithElem(name, attrs, _)
+                if name == "style" && get(attrs, "amp-custom").is_some() =>
+            {
+                XMLElem::Single(
+                    "link".to_owned(),
+                    vec![pair("rel", "stylesheet"), pair("href", stylesheet)],
+                )
+            }
+            _ => elem.clone(),
+        })
+        .collect::<Vec<_>>();
+    if let Some(amphtml) = amphtml {
+        converted.push(XMLElem::Single(
+            "link".to_owned(),
+            vec![pair("rel", "amphtml"), pair("href", amphtml)],
+        ));
+    }
+    converted
+}
+
+// AMPのページを通常のHTML5のページに変換する。
+// CSSはstylesheetへのリンクにし、amphtmlがあればAMP版へのリンクを加える
+pub fn from_amp(html: &XMLElem, stylesheet: &str, amphtml: Option<&str>) -> XMLElem {
+    match html {
+        XMLElem::WithElem(name, attrs, inner) if name == "html" => {
+            let attrs = attrs
+                .iter()
+                .filter(|attr| !matches!(attr, Attr::Single(name) if name == "amp" || name == "⚡"))
+                .cloned()
+                .collect();
+            let inner = inner
+                .iter()
+                .map(|elem| match elem {
+                    XMLElem::WithElem(name, attrs, head) if name == "head" => XMLElem::WithElem(
+                        name.to_owned(),
+                        attrs.clone(),
+                        convert_head(head, stylesheet, amphtml),
+                    ),
+                    _ => convert(elem),
+                })
+                .collect();
+            XMLElem::WithElem(name.to_owned(), attrs, inner)
+        }
+        _ => convert(html),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_amp() {
+        let head = vec![
+            XMLElem::Single("meta".to_owned(), vec![pair("charset", "UTF-8")]),
+            XMLElem::WithElem(
+                "script".to_owned(),
+                vec![
+                    Attr::Single("async".to_owned()),
+                    pair("src", "https://cdn.ampproject.org/v0.js"),
+                ],
+                vec![],
+            ),
+            XMLElem::WithElem(
+                "style".to_owned(),
+                vec![Attr::Single("amp-custom".to_owned())],
+                vec![XMLElem::Text("p{}".to_owned())],
+            ),
+            XMLElem::WithElem(
+                "noscript".to_owned(),
+                vec![],
+                vec![XMLElem::WithElem(
+                    "style".to_owned(),
+                    vec![Attr::Single("amp-boilerplate".to_owned())],
+                    vec![],
+                )],
+            ),
+        ];
+        let body = vec![
+            XMLElem::WithElem(
+                "amp-img".to_owned(),
+                vec![
+                    pair("src", "a.png"),
+                    pair("width", "4"),
+                    pair("height", "3"),
+                    pair("layout", "responsive"),
+                ],
+                vec![XMLElem::WithElem(
+                    "div".to_owned(),
+                    vec![
+                        Attr::Single("placeholder".to_owned()),
+                        pair("style", "background-color: #000000"),
+                    ],
+                    vec![],
+                )],
+            ),
+            XMLElem::WithElem(
+                "amp-youtube".to_owned(),
+                vec![
+                    pair("data-videoid", "dQw4w9WgXcQ"),
+                    pair("width", "480"),
+                    pair("height", "270"),
+                    pair("layout", "responsive"),
+                ],
+                vec![],
+            ),
+        ];
+        let html = XMLElem::WithElem(
+            "html".to_owned(),
+            vec![Attr::Single("amp".to_owned()), pair("lang", "ja")],
+            vec![
+                XMLElem::WithElem("head".to_owned(), vec![], head),
+                XMLElem::WithElem("body".to_owned(), vec![], body),
+            ],
+        );
+        let expected = XMLElem::WithElem(
+            "html".to_owned(),
+            vec![pair("lang", "ja")],
+            vec![
+                XMLElem::WithElem(
+                    "head".to_owned(),
+                    vec![],
+                    vec![
+                        XMLElem::Single("meta".to_owned(), vec![pair("charset", "UTF-8")]),
+                        XMLElem::Single(
+                            "link".to_owned(),
+                            vec![pair("rel", "stylesheet"), pair("href", "../style.css")],
+                        ),
+                        XMLElem::Single(
+                            "link".to_owned(),
+                            vec![
+                                pair("rel", "amphtml"),
+                                pair("href", "https://example.com/a.amp.html"),
+                            ],
+                        ),
+                    ],
+                ),
+                XMLElem::WithElem(
+                    "body".to_owned(),
+                    vec![],
+                    vec![
+                        XMLElem::Single(
+                            "img".to_owned(),
+                            vec![
+                                pair("src", "a.png"),
+                                pair("width", "4"),
+                                pair("height", "3"),
+                                pair(
+                                    "style",
+                                    "width: 100%; height: auto; aspect-ratio: 4 / 3; background-color: #000000",
+                                ),
+                                pair("loading", "lazy"),
+                                pair("decoding", "async"),
+                            ],
+                        ),
+                        XMLElem::WithElem(
+                            "iframe".to_owned(),
+                            vec![
+                                pair("width", "480"),
+                                pair("height", "270"),
+                                pair("style", "width: 100%; height: auto; aspect-ratio: 480 / 270"),
+                                pair("src", "https://www.youtube.com/embed/dQw4w9WgXcQ"),
+                                Attr::Single("allowfullscreen".to_owned()),
+                                pair("loading", "lazy"),
+                            ],
+                            vec![],
+                        ),
+                    ],
+                ),
+            ],
+        );
+        assert_eq!(
+            from_amp(
+                &html,
+                "../style.css",
+                Some("https://example.com/a.amp.html")
+            )
+            .to_string(),
+            expected.to_string()
+        );
+        assert_eq!(
+            amp_path(Path::new("articles/a.html")),
+            Path::new("articles/a.amp.html")
+        );
+    }
+}
@@ -0,0 +1,651 @@
+use super::Error;
+use image::error::{DecodingError, ImageFormatHint};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageError, ImageFormat, ImageOutputFormat};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+// srcsetに載せる縮小版の幅(px)
+pub const WIDTHS: [usize; 4] = [480, 800, 1200, 1600];
+// \galleryの一覧に使う縮小版の幅(px)
+pub const THUMBNAIL_WIDTH: usize = 320;
+const QUALITY: f32 = 80.0;
+// プレースホルダの縮小画像の長辺(px)
+const PLACEHOLDER_SIZE: u32 = 12;
+// 回転のためにJPEGを再エンコードする時の品質
+const JPEG_QUALITY: u8 = 90;
+// recompressが有効な時に容量制限に収まるまで順に試すJPEGの品質
+const BUDGET_QUALITIES: [u8; 4] = [85, 75, 65, 55];
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+// image crateでデコードする画像
+pub fn is_raster(path: &Path) -> bool {
+    ["webp", "png", "jpg", "jpeg", "gif"].contains(&extension(path).as_str())
+}
+
+// デコードせず大きさだけを読む画像
+pub fn is_measured(path: &Path) -> bool {
+    ["svg", "avif"].contains(&extension(path).as_str())
+}
+
+// アニメーションが失われるGIFと、デコードしない画像は縮小版を作らない
+pub fn has_variants(path: &Path) -> bool {
+    is_raster(path) && extension(path) != "gif"
+}
+
+// transcode-webpが有効な時にWebPへ変換する画像の出力先
+pub fn transcoded_path(path: &Path) -> Option<PathBuf> {
+    match extension(path).as_str() {
+        "png" | "jpg" | "jpeg" => Some(path.with_extension("webp")),
+        _ => None,
+    }
+}
+
+fn parse_length(length: &str) -> Option<f64> {
+    let length = length.trim();
+    length
+        .strip_suffix("px")
+        .unwrap_or(length)
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|l| *l > 0.0)
+}
+
+// ルートの<svg>のwidth/height、無ければviewBoxから大きさを決める
+pub fn svg_size(src: &str) -> Option<(usize, usize)> {
+    let tag_re = regex::Regex::new(r"<svg\b[^>]*>").unwrap();
+    let tag = tag_re.find(src)?.as_str();
+    let attr = |name: &str| {
+        regex::Regex::new(&format!(r#"\s{}\s*=\s*["']([^"']*)["']"#, name))
+            .unwrap()
+            .captures(tag)
+            .map(|caps| caps[1].to_owned())
+    };
+    let view_box = attr("viewBox").and_then(|vb| {
+        let nums = vb
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        match nums.as_slice() {
+            [_, _, w, h] if *w > 0.0 && *h > 0.0 => Some((*w, *h)),
+            _ => None,
+        }
+    });
+    let width = attr("width").and_then(|w| parse_length(&w));
+    let height = attr("height").and_then(|h| parse_length(&h));
+    let (w, h) = match (width, height, view_box) {
+        (Some(w), Some(h), _) => (w, h),
+        (Some(w), None, Some((vw, vh))) => (w, w * vh / vw),
+        (None, Some(h), Some((vw, vh))) => (h * vw / vh, h),
+        (_, _, Some(size)) => size,
+        _ => return None,
+    };
+    Some((w.round() as usize, h.round() as usize))
+}
+
+// AVIFはispeボックス(画像の空間的な大きさ)から幅と高さを読む
+pub fn avif_size(binary: &[u8]) -> Option<(usize, usize)> {
+    let idx = binary.windows(4).position(|w| w == b"ispe")?;
+    // ボックス名の後にversion/flagsが4バイト、続いて幅と高さがビッグエンディアンで並ぶ
+    let body = binary.get(idx + 8..idx + 16)?;
+    let read = |b: &[u8]| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+    Some((read(&body[..4]), read(&body[4..])))
+}
+
+pub fn measure(path: &Path, binary: &[u8]) -> Result<(usize, usize), Error> {
+    let size = match extension(path).as_str() {
+        "svg" => svg_size(&String::from_utf8_lossy(binary)),
+        _ => avif_size(binary),
+    };
+    size.ok_or_else(|| Error::ImageFmtError {
+        path: path.to_owned(),
+        desc: "Cannot determine image size".to_owned(),
+        because: ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::PathExtension(PathBuf::from(extension(path))),
+            "neither width/height nor a valid size is found",
+        )),
+    })
+}
+
+// EXIFのOrientationタグ。無ければ1(回転なし)
+fn orientation(binary: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(binary))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+// APP1(EXIF, XMP)とAPP13(IPTC)を取り除く。ICCプロファイルのAPP2などは残す
+fn strip_jpeg(binary: &[u8]) -> Option<Vec<u8>> {
+    if !binary.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut stripped = binary[..2].to_vec();
+    let mut pos = 2;
+    loop {
+        if *binary.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *binary.get(pos + 1)?;
+        // SOS以降は画像データなのでそのまま写す
+        if marker == 0xda {
+            stripped.extend_from_slice(&binary[pos..]);
+            return Some(stripped);
+        }
+        let len = u16::from_be_bytes([*binary.get(pos + 2)?, *binary.get(pos + 3)?]) as usize;
+        let segment = binary.get(pos..pos + 2 + len)?;
+        if marker != 0xe1 && marker != 0xed {
+            stripped.extend_from_slice(segment);
+        }
+        pos += 2 + len;
+    }
+}
+
+// eXIfとテキストチャンク(XMPはiTXtに入る)、tIMEを取り除く
+fn strip_png(binary: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !binary.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut stripped = SIGNATURE.to_vec();
+    let mut pos = SIGNATURE.len();
+    while pos < binary.len() {
+        let len = binary.get(pos..pos + 4)?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        let chunk = binary.get(pos..pos + 12 + len)?;
+        if ![&b"eXIf"[..], b"tEXt", b"zTXt", b"iTXt", b"tIME"].contains(&&chunk[4..8]) {
+            stripped.extend_from_slice(chunk);
+        }
+        pos += 12 + len;
+    }
+    Some(stripped)
+}
+
+// EXIFとXMPのチャンクを取り除き、VP8Xのフラグも落とす
+fn strip_webp(binary: &[u8]) -> Option<Vec<u8>> {
+    if binary.get(..4)? != b"RIFF" || binary.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut stripped = binary[..12].to_vec();
+    let mut pos = 12;
+    while pos < binary.len() {
+        let len = binary.get(pos + 4..pos + 8)?;
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        // チャンクは偶数バイトに揃えられる
+        let chunk = binary.get(pos..(pos + 8 + len + len % 2).min(binary.len()))?;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => (),
+            b"VP8X" => {
+                let mut chunk = chunk.to_vec();
+                *chunk.get_mut(8)? &= !0x0c;
+                stripped.extend_from_slice(&chunk);
+            }
+            _ => stripped.extend_from_slice(chunk),
+        }
+        pos += 8 + len + len % 2;
+    }
+    let riff_len = (stripped.len() - 8) as u32;
+    stripped[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Some(stripped)
+}
+
+fn encode_as(path: &Path, img: &DynamicImage, format: ImageOutputFormat) -> Result<Vec<u8>, Error> {
+    let mut encoded = Vec::new();
+    img.write_to(&mut encoded, format)
+        .map_err(|e| Error::ImageFmtError {
+            path: path.to_owned(),
+            desc: "Cannot encode image".to_owned(),
+            because: e,
+        })?;
+    Ok(encoded)
+}
+
+// 向きを反映した画像を再エンコードする。GIFはEXIFを持たないので触らない
+fn reencode(path: &Path, img: &DynamicImage) -> Result<Vec<u8>, Error> {
+    match extension(path).as_str() {
+        "jpg" | "jpeg" => encode_as(path, img, ImageOutputFormat::Jpeg(JPEG_QUALITY)),
+        "webp" => {
+            let rgba = img.to_rgba8();
+            Ok(webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode(QUALITY)
+                .to_vec())
+        }
+        _ => encode_as(path, img, ImageOutputFormat::Png),
+    }
+}
+
+// 位置情報などを含むメタデータを取り除き、Orientationタグに従って回転させる。
+// 回転が不要ならメタデータのセグメントを落とすだけで画素は再エンコードしない
+pub fn sanitize(
+    path: &Path,
+    img: DynamicImage,
+    binary: Vec<u8>,
+) -> Result<(DynamicImage, Vec<u8>), Error> {
+    let orientation = orientation(&binary);
+    if orientation != 1 {
+        let img = apply_orientation(img, orientation);
+        let encoded = reencode(path, &img)?;
+        return Ok((img, encoded));
+    }
+    let stripped = match extension(path).as_str() {
+        "jpg" | "jpeg" => strip_jpeg(&binary),
+        "png" => strip_png(&binary),
+        "webp" => strip_webp(&binary),
+        _ => return Ok((img, binary)),
+    };
+    match stripped {
+        Some(stripped) => Ok((img, stripped)),
+        None => {
+            warn!("cannot strip metadata of {:?} without re-encoding it", path);
+            let encoded = reencode(path, &img)?;
+            Ok((img, encoded))
+        }
+    }
+}
+
+// SVGから<metadata>と編集ソフトの情報(ファイルのパスなどを含む)を取り除く
+fn strip_svg(src: &str) -> String {
+    let elems = regex::Regex::new(
+        r"(?s)<(metadata|sodipodi:namedview)\b[^>]*/>|<(metadata|sodipodi:namedview)\b.*?</(metadata|sodipodi:namedview)>",
+    )
+    .unwrap();
+    let attrs =
+        regex::Regex::new(r#"\s(inkscape|sodipodi):[\w.-]+\s*=\s*("[^"]*"|'[^']*')"#).unwrap();
+    attrs
+        .replace_all(&elems.replace_all(src, ""), "")
+        .into_owned()
+}
+
+// AVIFのmetaボックスにEXIFかXMPのアイテムがあるか
+fn avif_has_metadata(binary: &[u8]) -> bool {
+    let mut pos = 0;
+    while let Some(header) = binary.get(pos..pos + 8) {
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let end = if size == 0 { binary.len() } else { pos + size };
+        if size != 0 && size < 8 {
+            return false;
+        }
+        if &header[4..] == b"meta" {
+            let meta = &binary[pos..end.min(binary.len())];
+            return meta.windows(4).any(|w| w == b"Exif")
+                || meta.windows(19).any(|w| w == b"application/rdf+xml");
+        }
+        pos = end;
+    }
+    false
+}
+
+// SVGやAVIFのメタデータを取り除く。AVIFは書き換えられないので警告に留める
+pub fn sanitize_measured(path: &Path, binary: Vec<u8>) -> Vec<u8> {
+    match extension(path).as_str() {
+        "svg" => match String::from_utf8(binary) {
+            Ok(src) => strip_svg(&src).into_bytes(),
+            Err(e) => e.into_bytes(),
+        },
+        _ => {
+            if avif_has_metadata(&binary) {
+                warn!(
+                    "{:?} has EXIF or XMP metadata that cannot be stripped from AVIF. remove it before publishing",
+                    path
+                );
+            }
+            binary
+        }
+    }
+}
+
+// 容量を超えるJPEGを品質を下げながら再エンコードする。収まらなければNone
+pub fn recompress(
+    path: &Path,
+    img: &DynamicImage,
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    if !["jpg", "jpeg"].contains(&extension(path).as_str()) {
+        return Ok(None);
+    }
+    for quality in BUDGET_QUALITIES.iter() {
+        let encoded = encode_as(path, img, ImageOutputFormat::Jpeg(*quality))?;
+        if encoded.len() <= max_bytes {
+            return Ok(Some(encoded));
+        }
+    }
+    Ok(None)
+}
+
+// 画像の読み込み中に表示する平均色と、ぼかして表示する縮小画像
+#[derive(Debug, PartialEq)]
+pub struct Placeholder {
+    pub color: String,
+    pub data_uri: String,
+}
+
+pub fn placeholder(path: &Path, img: &DynamicImage) -> Result<Placeholder, Error> {
+    let rgb = img.to_rgb8();
+    let mut sum = [0u64; 3];
+    for pixel in rgb.pixels() {
+        for (s, c) in sum.iter_mut().zip(pixel.0.iter()) {
+            *s += *c as u64;
+        }
+    }
+    let count = (rgb.width() as u64 * rgb.height() as u64).max(1);
+    let [r, g, b] = [sum[0] / count, sum[1] / count, sum[2] / count];
+    let thumbnail = img.thumbnail(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE);
+    let png = encode_as(
+        path,
+        &DynamicImage::ImageRgb8(thumbnail.to_rgb8()),
+        ImageOutputFormat::Png,
+    )?;
+    Ok(Placeholder {
+        color: format!("#{:02x}{:02x}{:02x}", r, g, b),
+        data_uri: format!("data:image/png;base64,{}", base64::encode(&png)),
+    })
+}
+
+fn cached<F>(cache_dir: Option<&Path>, key: &str, f: F) -> Result<Vec<u8>, Error>
+where
+    F: FnOnce() -> Vec<u8>,
+{
+    let cache_path = cache_dir.map(|dir| dir.join(key));
+    if let Some(cached) = cache_path.as_ref().and_then(|p| fs::read(p).ok()) {
+        return Ok(cached);
+    }
+    let encoded = f();
+    if let Some(cache_path) = cache_path {
+        fs::create_dir_all(cache_path.parent().unwrap())
+            .and_then(|_| fs::write(&cache_path, &encoded))
+            .map_err(|e| Error::FsError {
+                path: cache_path.to_owned(),
+                desc: "Cannot write image cache".to_owned(),
+                because: e,
+            })?;
+    }
+    Ok(encoded)
+}
+
+fn hash(binary: &[u8]) -> String {
+    hex::encode(Sha256::digest(binary).as_slice())
+}
+
+// PNGは可逆、JPEGは非可逆でWebPにする
+pub fn transcode(
+    img: &DynamicImage,
+    binary: &[u8],
+    cache_dir: Option<&Path>,
+) -> Result<Vec<u8>, Error> {
+    let lossless = image::guess_format(binary).ok() == Some(ImageFormat::Png);
+    cached(cache_dir, &format!("{}.webp", hash(binary)), || {
+        let rgba = img.to_rgba8();
+        let encoder = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height());
+        if lossless {
+            encoder.encode_lossless().to_vec()
+        } else {
+            encoder.encode(QUALITY).to_vec()
+        }
+    })
+}
+
+// 元画像より小さい幅だけを縮小版として作る
+pub fn variant_widths(width: usize) -> Vec<usize> {
+    WIDTHS.iter().copied().filter(|w| *w < width).collect()
+}
+
+// res/foo.png -> res/foo-png-800w.webp。拡張子を含めてres/foo.jpgの縮小版と区別する
+pub fn variant_path(path: &Path, width: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}-{}-{}w.webp", stem, extension(path), width))
+}
+
+fn encode(img: &DynamicImage, width: usize) -> Vec<u8> {
+    let height = (img.height() as f64 * width as f64 / img.width() as f64).round() as u32;
+    let resized = img
+        .resize_exact(width as u32, height.max(1), FilterType::Lanczos3)
+        .to_rgba8();
+    webp::Encoder::from_rgba(&resized, resized.width(), resized.height())
+        .encode(QUALITY)
+        .to_vec()
+}
+
+// 縮小版を生成する。cache_dirがあれば元画像のハッシュをキーに再利用する
+pub fn generate_variants(
+    path: &Path,
+    img: &DynamicImage,
+    binary: &[u8],
+    cache_dir: Option<&Path>,
+) -> Result<Vec<(PathBuf, Vec<u8>)>, Error> {
+    if !has_variants(path) {
+        return Ok(Vec::new());
+    }
+    let hash = hash(binary);
+    variant_widths(img.width() as usize)
+        .into_iter()
+        .map(|width| {
+            let encoded = cached(cache_dir, &format!("{}-{}w.webp", hash, width), || {
+                encode(img, width)
+            })?;
+            Ok((variant_path(path, width), encoded))
+        })
+        .collect()
+}
+
+// \galleryで使う画像の一覧用の縮小版。元画像が十分小さければ作らない
+pub fn generate_thumbnail(
+    path: &Path,
+    img: &DynamicImage,
+    binary: &[u8],
+    cache_dir: Option<&Path>,
+) -> Result<Option<(PathBuf, Vec<u8>)>, Error> {
+    if !has_variants(path) || img.width() as usize <= THUMBNAIL_WIDTH {
+        return Ok(None);
+    }
+    let encoded = cached(
+        cache_dir,
+        &format!("{}-{}w.webp", hash(binary), THUMBNAIL_WIDTH),
+        || encode(img, THUMBNAIL_WIDTH),
+    )?;
+    Ok(Some((variant_path(path, THUMBNAIL_WIDTH), encoded)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_variants() {
+        assert_eq!(variant_widths(1000), vec![480, 800]);
+        assert_eq!(variant_widths(480), Vec::<usize>::new());
+        assert_eq!(
+            variant_path(Path::new("res/foo.webp"), 800),
+            Path::new("res/foo-webp-800w.webp")
+        );
+        assert_ne!(
+            variant_path(Path::new("res/foo.png"), 800),
+            variant_path(Path::new("res/foo.jpg"), 800)
+        );
+        let img = DynamicImage::new_rgb8(600, 300);
+        let variants = generate_variants(Path::new("a.webp"), &img, b"a", None).unwrap();
+        assert_eq!(variants.len(), 1);
+        let decoded = image::load_from_memory(&variants[0].1).unwrap();
+        assert_eq!(decoded.dimensions(), (480, 240));
+        assert!(generate_variants(Path::new("a.gif"), &img, b"a", None)
+            .unwrap()
+            .is_empty());
+        let (path, thumbnail) = generate_thumbnail(Path::new("a.webp"), &img, b"a", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(path, Path::new("a-webp-320w.webp"));
+        let decoded = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!(decoded.dimensions(), (320, 160));
+    }
+
+    // Orientationだけを持つEXIFのAPP1セグメント
+    fn exif_app1(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"Exif\0\0II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff.extend_from_slice(&[0; 6]);
+        let mut app1 = vec![0xff, 0xe1];
+        app1.extend_from_slice(&(tiff.len() as u16 + 2).to_be_bytes());
+        app1.extend_from_slice(&tiff);
+        app1
+    }
+
+    fn with_exif(jpeg: &[u8], orientation: u16) -> Vec<u8> {
+        let mut binary = jpeg[..2].to_vec();
+        binary.extend_from_slice(&exif_app1(orientation));
+        binary.extend_from_slice(&jpeg[2..]);
+        binary
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let img = DynamicImage::new_rgb8(40, 20);
+        let path = Path::new("a.jpg");
+        let jpeg = encode_as(path, &img, ImageOutputFormat::Jpeg(90)).unwrap();
+
+        let binary = with_exif(&jpeg, 1);
+        assert_eq!(orientation(&binary), 1);
+        let (img, stripped) = sanitize(path, img, binary).unwrap();
+        assert_eq!(stripped, jpeg);
+        assert_eq!(img.dimensions(), (40, 20));
+
+        let binary = with_exif(&jpeg, 6);
+        assert_eq!(orientation(&binary), 6);
+        let (img, rotated) = sanitize(path, img, binary).unwrap();
+        assert_eq!(img.dimensions(), (20, 40));
+        assert_eq!(orientation(&rotated), 1);
+        assert!(!rotated.windows(4).any(|w| w == b"Exif"));
+    }
+
+    #[test]
+    fn test_strip_png() {
+        let png = encode_as(
+            Path::new("a.png"),
+            &DynamicImage::new_rgb8(4, 4),
+            ImageOutputFormat::Png,
+        )
+        .unwrap();
+        // IHDRの直後にtEXtチャンクを挟む
+        let text = b"\0\0\0\x07tEXtGPS\0abc\0\0\0\0";
+        let mut binary = png[..33].to_vec();
+        binary.extend_from_slice(text);
+        binary.extend_from_slice(&png[33..]);
+        assert_eq!(strip_png(&binary).unwrap(), png);
+        assert_eq!(strip_png(b"GIF89a"), None);
+    }
+
+    #[test]
+    fn test_strip_webp() {
+        let mut binary = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0\x0c\0\0\0\0\0\0\0\0\0".to_vec();
+        binary.extend_from_slice(b"EXIF\x03\0\0\0abc\0");
+        binary.extend_from_slice(b"VP8L\x02\0\0\0xy");
+        let stripped = strip_webp(&binary).unwrap();
+        assert_eq!(stripped.len(), 12 + 18 + 10);
+        assert_eq!(stripped[20], 0);
+        assert_eq!(&stripped[4..8], &(stripped.len() as u32 - 8).to_le_bytes());
+        assert!(!stripped.windows(4).any(|w| w == b"EXIF"));
+    }
+
+    #[test]
+    fn test_placeholder() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            300,
+            100,
+            image::Rgb([255, 128, 0]),
+        ));
+        let placeholder = placeholder(Path::new("a.png"), &img).unwrap();
+        assert_eq!(placeholder.color, "#ff8000");
+        let png = base64::decode(
+            placeholder
+                .data_uri
+                .strip_prefix("data:image/png;base64,")
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(image::load_from_memory(&png).unwrap().dimensions(), (12, 4));
+    }
+
+    #[test]
+    fn test_svg_size() {
+        assert_eq!(
+            svg_size(
+                r#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" width="120px" height="60"></svg>"#
+            ),
+            Some((120, 60))
+        );
+        assert_eq!(
+            svg_size(
+                r#"<svg viewBox="0 0 300 150" width="100%"><rect width="10" height="10"/></svg>"#
+            ),
+            Some((300, 150))
+        );
+        assert_eq!(
+            svg_size(r#"<svg width="200" viewBox="0,0,300,150">"#),
+            Some((200, 100))
+        );
+        assert_eq!(svg_size(r#"<svg width="1em">"#), None);
+    }
+
+    #[test]
+    fn test_avif_size() {
+        let mut binary = b"\0\0\0\x14ispe\0\0\0\0".to_vec();
+        binary.extend_from_slice(&640u32.to_be_bytes());
+        binary.extend_from_slice(&480u32.to_be_bytes());
+        assert_eq!(avif_size(&binary), Some((640, 480)));
+        assert_eq!(avif_size(b"ispe"), None);
+    }
+
+    #[test]
+    fn test_sanitize_measured() {
+        let svg = concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" sodipodi:docname="/home/me/a.svg" width="10" height="10">"#,
+            r#"<metadata><rdf:RDF><dc:creator>me</dc:creator></rdf:RDF></metadata>"#,
+            r#"<sodipodi:namedview inkscape:zoom="1"/>"#,
+            r#"<rect inkscape:label='r' width="10" height="10"/></svg>"#
+        );
+        assert_eq!(
+            String::from_utf8(sanitize_measured(
+                Path::new("a.svg"),
+                svg.as_bytes().to_vec()
+            ))
+            .unwrap(),
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="10" height="10"/></svg>"#
+        );
+        let mut avif = b"\0\0\0\x10ftypavif\0\0\0\0".to_vec();
+        avif.extend_from_slice(b"\0\0\0\x17meta\0\0\0\0infe\0\0Exif\0");
+        assert!(avif_has_metadata(&avif));
+        assert!(!avif_has_metadata(&avif[..16]));
+        // mdatの中の偶然の一致は無視する
+        assert!(!avif_has_metadata(b"\0\0\0\x0cmdatExif"));
+    }
+}
@@ -6,11 +6,23 @@ extern crate zip;
 
 #[macro_use]
 pub mod xml;
+pub mod amp;
 pub mod analysis;
+pub mod bib;
+pub mod config;
 pub mod convert;
+pub mod css;
+pub mod csv;
+pub mod diff;
+pub mod html5;
+pub mod images;
+pub mod math;
+pub mod og;
 pub mod parser;
+pub mod remote;
 
-use log::info;
+use image::GenericImageView;
+use log::{info, warn};
 use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
@@ -21,7 +33,7 @@ use std::io::{Seek, Write};
 use std::path::Path;
 use std::path::PathBuf;
 
-#[derive(Debug, PartialEq, Clone, Eq)]
+#[derive(Debug, PartialEq, Clone, Eq, Hash)]
 pub struct Position {
     fname: String,
     // 1-indexed
@@ -34,10 +46,11 @@ pub struct Position {
 pub enum Error {
     SyntaxError(Location),
     Internal(String),
+    // Resultが大きくならないよう、位置の他に2つ以上の値を持つ列挙子では位置を箱に入れる
     InvalidLink {
         link: PathBuf,
         msg: String,
-        loc: Location,
+        loc: Box<Location>,
     },
     MissingAttribute {
         name: String,
@@ -47,12 +60,12 @@ pub enum Error {
         name: String,
         expected: ValueType,
         found: ValueType,
-        loc: Location,
+        loc: Box<Location>,
     },
     InvalidAttribute {
         name: String,
         reason: String,
-        loc: Location,
+        loc: Box<Location>,
     },
     NoSuchCmd {
         name: String,
@@ -81,6 +94,17 @@ pub enum Error {
         desc: String,
         because: io::Error,
     },
+    ConfigError {
+        path: PathBuf,
+        desc: String,
+    },
+    RemoteImageError {
+        url: String,
+        desc: String,
+    },
+    InvalidAmp {
+        violations: Vec<(Location, String)>,
+    },
 }
 
 impl Position {
@@ -117,7 +141,7 @@ impl Ord for Position {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Eq, Hash)]
 pub enum Location {
     Span(Position, Position),
     At(Position),
@@ -592,7 +616,7 @@ pub mod value_utils {
             v.str()
                 .ok_or(Error::InvalidAttributeType {
                     name: name.to_owned(),
-                    loc: loc.to_owned(),
+                    loc: Box::new(loc.to_owned()),
                     expected: ValueType::Str,
                     found: v.value_type(),
                 })
@@ -606,7 +630,7 @@ pub mod value_utils {
         let v = access(attrs, name, loc)?;
         v.str().ok_or(Error::InvalidAttributeType {
             name: name.to_owned(),
-            loc: loc.to_owned(),
+            loc: Box::new(loc.to_owned()),
             expected: ValueType::Str,
             found: v.value_type(),
         })
@@ -621,7 +645,7 @@ pub mod value_utils {
             v.int()
                 .ok_or(Error::InvalidAttributeType {
                     name: name.to_owned(),
-                    loc: loc.to_owned(),
+                    loc: Box::new(loc.to_owned()),
                     expected: ValueType::Str,
                     found: v.value_type(),
                 })
@@ -635,7 +659,7 @@ pub mod value_utils {
         let v = access(attrs, name, loc)?;
         v.int().ok_or(Error::InvalidAttributeType {
             name: name.to_owned(),
-            loc: loc.to_owned(),
+            loc: Box::new(loc.to_owned()),
             expected: ValueType::Int,
             found: v.value_type(),
         })
@@ -650,7 +674,7 @@ pub mod value_utils {
             v.float()
                 .ok_or(Error::InvalidAttributeType {
                     name: name.to_owned(),
-                    loc: loc.to_owned(),
+                    loc: Box::new(loc.to_owned()),
                     expected: ValueType::Str,
                     found: v.value_type(),
                 })
@@ -664,7 +688,7 @@ pub mod value_utils {
         let v = access(attrs, name, loc)?;
         v.float().ok_or(Error::InvalidAttributeType {
             name: name.to_owned(),
-            loc: loc.to_owned(),
+            loc: Box::new(loc.to_owned()),
             expected: ValueType::Float,
             found: v.value_type(),
         })
@@ -679,7 +703,7 @@ pub mod value_utils {
             v.text()
                 .ok_or(Error::InvalidAttributeType {
                     name: name.to_owned(),
-                    loc: loc.to_owned(),
+                    loc: Box::new(loc.to_owned()),
                     expected: ValueType::Str,
                     found: v.value_type(),
                 })
@@ -697,7 +721,7 @@ pub mod value_utils {
         let v = access(attrs, name, loc)?;
         v.text().ok_or(Error::InvalidAttributeType {
             name: name.to_owned(),
-            loc: loc.to_owned(),
+            loc: Box::new(loc.to_owned()),
             expected: ValueType::Float,
             found: v.value_type(),
         })
@@ -714,7 +738,7 @@ pub mod value_utils {
             if !v.is_instanceof(&typ) {
                 Err(Error::InvalidAttributeType {
                     name: name.to_owned(),
-                    loc: loc.to_owned(),
+                    loc: Box::new(loc.to_owned()),
                     expected: ValueType::Str,
                     found: v.value_type(),
                 })
@@ -736,7 +760,7 @@ pub mod value_utils {
         if !v.is_instanceof(&typ) {
             return Err(Error::InvalidAttributeType {
                 name: name.to_owned(),
-                loc: loc.to_owned(),
+                loc: Box::new(loc.to_owned()),
                 expected: typ,
                 found: v.value_type(),
             });
@@ -766,6 +790,8 @@ type TextElemAst = (TextElem, Location);
 pub enum File {
     Tml((Cmd, Location), String),
     Image(image::DynamicImage, Vec<u8>),
+    // SVGやAVIFのようにデコードせず大きさだけを読んだ画像
+    MeasuredImage((usize, usize), Vec<u8>),
     Blob(Vec<u8>),
 }
 
@@ -790,7 +816,88 @@ where
     paths
 }
 
-pub fn compile_and_write<W: Write + Seek, P>(writer: &mut W, dir_path: P) -> Result<(), Error>
+// 変換にだけ使い、出力に含めないファイルの拡張子
+const BUILD_INPUT_EXTENSIONS: [&str; 4] = ["bib", "csv", "sublime-syntax", "tmTheme"];
+
+// 設定やデータ、コード片など変換の入力にだけ使うファイルか。pathはサイトのルートからの相対パス
+pub fn is_build_input(path: &Path) -> bool {
+    path == Path::new(config::CONFIG_FILE)
+        || path == Path::new(remote::LOCKFILE)
+        || path.starts_with(convert::SNIPPETS_DIR)
+        || path
+            .extension()
+            .and_then(OsStr::to_str)
+            .map(|ext| BUILD_INPUT_EXTENSIONS.contains(&ext))
+            .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test_build_input {
+    use super::*;
+    #[test]
+    fn test_is_build_input() {
+        assert!(is_build_input(Path::new("config.json")));
+        assert!(is_build_input(Path::new("remote-images.lock")));
+        assert!(is_build_input(Path::new("bibliography.bib")));
+        assert!(is_build_input(Path::new("data/table.csv")));
+        assert!(is_build_input(Path::new("syntaxes/Nix.sublime-syntax")));
+        assert!(is_build_input(Path::new("Mine.tmTheme")));
+        assert!(is_build_input(Path::new("snippets/main.rs")));
+        assert!(!is_build_input(Path::new("articles/config.json")));
+        assert!(!is_build_input(Path::new("res/a.png")));
+        assert!(!is_build_input(Path::new("index.css")));
+    }
+}
+
+// 出力先が他のファイルと重なれば上書きせずにエラーにする
+fn add_output(
+    out: &mut HashMap<PathBuf, (Vec<u8>, String)>,
+    path: PathBuf,
+    binary: Vec<u8>,
+    source: String,
+) -> Result<(), Error> {
+    if let Some((_, other)) = out.get(&path) {
+        return Err(Error::ProcessError {
+            loc: Location::Generated,
+            desc: format!("{:?} is generated from both {} and {}", path, other, source),
+        });
+    }
+    out.insert(path, (binary, source));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_output {
+    use super::*;
+    #[test]
+    fn test_add_output() {
+        let mut out = HashMap::new();
+        add_output(
+            &mut out,
+            PathBuf::from("a.webp"),
+            vec![],
+            "\"a.webp\"".to_owned(),
+        )
+        .unwrap();
+        let err = add_output(
+            &mut out,
+            PathBuf::from("a.webp"),
+            vec![],
+            "the WebP of \"a.png\"".to_owned(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, Error::ProcessError { desc, .. } if desc.contains("generated from both \"a.webp\" and the WebP"))
+        );
+    }
+}
+
+// cache_dirを指定すると画像の縮小版をそこに保存して次回以降再利用する
+pub fn compile_and_write<W: Write + Seek, P>(
+    writer: &mut W,
+    dir_path: P,
+    cache_dir: Option<&Path>,
+) -> Result<(), Error>
 where
     P: AsRef<Path>,
 {
@@ -816,7 +923,7 @@ where
                     .to_owned(),
                 File::Tml(ast, source),
             );
-        } else if p.extension() == Some(OsStr::new("webp")) {
+        } else if images::is_raster(p) {
             let binary = fs::read(p).map_err(|e| Error::FsError {
                 path: p.to_owned(),
                 desc: "Cannot read blob file".to_owned(),
@@ -828,10 +935,22 @@ where
                     desc: "Cannot load image".to_owned(),
                     because: e,
                 })?;
+            let (img, binary) = images::sanitize(p, img, binary)?;
             files.insert(
                 p.strip_prefix(&dir_path).unwrap().to_owned(),
                 File::Image(img, binary),
             );
+        } else if images::is_measured(p) {
+            let binary = fs::read(p).map_err(|e| Error::FsError {
+                path: p.to_owned(),
+                desc: "Cannot read blob file".to_owned(),
+                because: e,
+            })?;
+            let size = images::measure(p, &binary)?;
+            files.insert(
+                p.strip_prefix(&dir_path).unwrap().to_owned(),
+                File::MeasuredImage(size, images::sanitize_measured(p, binary)),
+            );
         } else {
             info!("add blob {:?}", p);
             let binary = fs::read(p).map_err(|e| Error::FsError {
@@ -849,24 +968,96 @@ where
     let generated_files = convert::generate_category_pages(&report, &report.css)?
         .into_iter()
         .map(|(p, xml)| (p, xml.pretty_print().into_bytes()));
-    let mut out = files
-        .into_iter()
-        .map(|(p, file)| match file {
-            File::Blob(binary) => Ok((p, binary)),
+    let limits = &report.config.images;
+    for (p, file) in files.iter_mut() {
+        if let File::Image(img, binary) = file {
+            let pixels = img.width() as usize * img.height() as usize;
+            if let Some(max_pixels) = limits.max_pixels.filter(|max| pixels > *max) {
+                warn!("{:?} has {} pixels (limit {})", p, pixels, max_pixels);
+            }
+            if let Some(max_bytes) = limits.max_bytes.filter(|max| binary.len() > *max) {
+                let recompressed = if limits.recompress {
+                    images::recompress(p, img, max_bytes)?
+                } else {
+                    None
+                };
+                if let Some(recompressed) = recompressed {
+                    info!(
+                        "recompress {:?} ({} -> {} bytes)",
+                        p,
+                        binary.len(),
+                        recompressed.len()
+                    );
+                    *binary = recompressed;
+                } else {
+                    warn!("{:?} has {} bytes (limit {})", p, binary.len(), max_bytes);
+                }
+            }
+        }
+    }
+    // 生成するファイルと、エラーで示すその生成元
+    let mut variants = Vec::new();
+    for (p, file) in &files {
+        if let File::Image(img, binary) = file {
+            for (path, encoded) in images::generate_variants(p, img, binary, cache_dir)? {
+                variants.push((path, encoded, format!("the resized {:?}", p)));
+            }
+            if report.thumbnails.contains(p) {
+                if let Some((path, encoded)) =
+                    images::generate_thumbnail(p, img, binary, cache_dir)?
+                {
+                    variants.push((path, encoded, format!("the thumbnail of {:?}", p)));
+                }
+            }
+            if let Some(webp_path) = images::transcoded_path(p) {
+                if report.transcoded.contains(p) {
+                    let encoded = images::transcode(img, binary, cache_dir)?;
+                    variants.push((webp_path, encoded, format!("the WebP of {:?}", p)));
+                }
+            }
+        }
+    }
+    for (p, card) in &report.og_cards {
+        let rendered = og::render(&report.og_fonts, card);
+        variants.push((p.to_owned(), rendered, "the OG image".to_owned()));
+    }
+    if report.config.output.profile != config::Profile::Amp {
+        variants.push((
+            PathBuf::from(html5::STYLESHEET),
+            report.css.clone().into_bytes(),
+            "the generated stylesheet".to_owned(),
+        ));
+    }
+    let mut out = HashMap::new();
+    for (p, file) in files {
+        match file {
+            File::Blob(_) if is_build_input(&p) || report.is_snippet(&p) => {
+                info!("skip build input {:?}", p);
+            }
+            File::Blob(binary) | File::Image(_, binary) | File::MeasuredImage(_, binary) => {
+                let source = format!("{:?}", p);
+                add_output(&mut out, p, binary, source)?;
+            }
             File::Tml(cmd, _) => {
-                let xml = convert::root(report.get_context(&p).unwrap(), cmd.0)?;
-                Ok((p, xml.pretty_print().into_bytes()))
+                let source = format!("{:?}", p.with_extension("tml"));
+                for (p, xml) in convert::root(report.get_context(&p).unwrap(), cmd.0)? {
+                    add_output(&mut out, p, xml.pretty_print().into_bytes(), source.clone())?;
+                }
             }
-            File::Image(_, binary) => Ok((p, binary)),
-        })
-        .collect::<Result<HashMap<_, _>, Error>>()?;
-    out.extend(generated_files);
+        }
+    }
+    for (p, binary) in generated_files {
+        add_output(&mut out, p, binary, "the category page".to_owned())?;
+    }
+    for (p, binary, source) in variants {
+        add_output(&mut out, p, binary, source)?;
+    }
     let dist_writer = io::BufWriter::new(writer);
     let mut dist_zip = zip::ZipWriter::new(dist_writer);
     let options = zip::write::FileOptions::default()
         .compression_method(zip::CompressionMethod::Deflated)
         .unix_permissions(0o444);
-    for (p, bin) in out {
+    for (p, (bin, _)) in out {
         info!("saving {:?}", p);
         dist_zip
             .start_file_from_path(&p, options)
@@ -885,3 +1076,59 @@ where
     })?;
     Ok(())
 }
+
+// 記事が参照する外部画像の大きさを取得してremote-images.lockを更新する。
+// 通常のビルドはネットワークに触れず、このファイルだけを読む
+pub fn fetch_remote_images<P>(dir_path: P) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    let mut files = HashMap::new();
+    for p in enumerate_all_file_paths(&dir_path) {
+        if p.extension() != Some(OsStr::new("tml")) {
+            continue;
+        }
+        let source = fs::read_to_string(&p).map_err(|e| Error::FsError {
+            path: p.to_owned(),
+            desc: "Cannot read tml file".to_owned(),
+            because: e,
+        })?;
+        let fname = p
+            .as_os_str()
+            .to_str()
+            .ok_or_else(|| Error::CannotInterpretPathAsUTF8(p.to_owned()))?;
+        let ast = parser::parse(fname, &source)?;
+        files.insert(p.to_owned(), File::Tml(ast, source));
+    }
+    let lock_path = dir_path.as_ref().join(remote::LOCKFILE);
+    let old_lock = match fs::read(&lock_path) {
+        Ok(src) => remote::parse_lock(&lock_path, &src)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => remote::Lock::new(),
+        Err(e) => {
+            return Err(Error::FsError {
+                path: lock_path,
+                desc: "Cannot read lockfile".to_owned(),
+                because: e,
+            })
+        }
+    };
+    let mut lock = remote::Lock::new();
+    for url in analysis::remote_image_urls(&files)? {
+        let size = if let Some(size) = old_lock.get(&url) {
+            *size
+        } else {
+            info!("fetching {}", url);
+            remote::fetch_size(&url)?
+        };
+        lock.insert(url, size);
+    }
+    // 参照されなくなった画像は取り除く
+    for url in old_lock.keys().filter(|url| !lock.contains_key(*url)) {
+        info!("remove {} from {}", url, remote::LOCKFILE);
+    }
+    fs::write(&lock_path, remote::serialize_lock(&lock)).map_err(|e| Error::FsError {
+        path: lock_path.to_owned(),
+        desc: "Cannot write lockfile".to_owned(),
+        because: e,
+    })
+}
@@ -1,6 +1,6 @@
 extern crate engine;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use engine::Error;
 use log::{error, info};
 use std::fs;
@@ -100,6 +100,21 @@ fn handle_error(e: Error) -> ! {
             error!("{} invalid attribute at {}. {}", loc, name, reason);
             exit(-1);
         }
+        Error::ConfigError { path, desc } => {
+            error!("invalid config {:?}. {}", path, desc);
+            exit(-1);
+        }
+        Error::RemoteImageError { url, desc } => {
+            error!("cannot fetch remote image {}. {}", url, desc);
+            exit(-1);
+        }
+        Error::InvalidAmp { violations } => {
+            for (loc, msg) in &violations {
+                error!("{} invalid AMP. {}", loc, msg);
+            }
+            error!("{} AMP violations", violations.len());
+            exit(-1);
+        }
         Error::CannotInterpretPathAsUTF8(path) => {
             error!(
                 "cannot interpret path {:?}. all paths must be encoded by UTF-8",
@@ -114,14 +129,35 @@ fn main() {
     env_logger::init();
     info!("start compiling");
     let matches = App::new("engine")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("SOURCE").required(true).takes_value(true))
         .arg(Arg::with_name("DEST").required(true).takes_value(true))
+        .arg(
+            Arg::with_name("cache-dir")
+                .long("cache-dir")
+                .takes_value(true)
+                .default_value(".engine-cache")
+                .help("directory to keep resized images between builds"),
+        )
+        .subcommand(
+            SubCommand::with_name("fetch-images")
+                .about("record sizes of remote images in remote-images.lock")
+                .arg(Arg::with_name("SOURCE").required(true).takes_value(true)),
+        )
         .get_matches();
+    if let Some(matches) = matches.subcommand_matches("fetch-images") {
+        let dir_path = Path::new(matches.value_of("SOURCE").unwrap());
+        if let Err(e) = engine::fetch_remote_images(&dir_path) {
+            handle_error(e);
+        }
+        return;
+    }
     let dir_path = Path::new(matches.value_of("SOURCE").unwrap());
     let dist_path = Path::new(matches.value_of("DEST").unwrap());
+    let cache_dir = Path::new(matches.value_of("cache-dir").unwrap());
     match fs::File::create(&dist_path) {
         Ok(mut writer) => {
-            if let Err(e) = engine::compile_and_write(&mut writer, &dir_path) {
+            if let Err(e) = engine::compile_and_write(&mut writer, &dir_path, Some(cache_dir)) {
                 fs::remove_file(&dist_path).ok();
                 handle_error(e);
             }
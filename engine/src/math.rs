@@ -0,0 +1,1063 @@
+use super::xml::{Attr, XMLElem};
+use super::{Error, Location};
+
+type MResult<T> = Result<T, Error>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Macro(String),
+    Open,
+    Close,
+    Sup,
+    Sub,
+    Amp,
+    Space,
+    Number(String),
+    Char(char),
+}
+
+fn tokenize(src: &str) -> Vec<(Token, usize)> {
+    let chars = src.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let token = match chars[i] {
+            '\\' => {
+                i += 1;
+                let mut name = String::new();
+                while i < chars.len() && chars[i].is_ascii_alphabetic() {
+                    name.push(chars[i]);
+                    i += 1;
+                }
+                if name.is_empty() && i < chars.len() {
+                    name.push(chars[i]);
+                    i += 1;
+                }
+                tokens.push((Token::Macro(name), start));
+                continue;
+            }
+            '{' => Token::Open,
+            '}' => Token::Close,
+            '^' => Token::Sup,
+            '_' => Token::Sub,
+            '&' => Token::Amp,
+            c if c.is_whitespace() => Token::Space,
+            c if c.is_ascii_digit() => {
+                let mut n = String::new();
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    n.push(chars[i]);
+                    i += 1;
+                }
+                tokens.push((Token::Number(n), start));
+                continue;
+            }
+            c => Token::Char(c),
+        };
+        tokens.push((token, start));
+        i += 1;
+    }
+    tokens
+}
+
+fn leaf(name: &str, text: &str) -> XMLElem {
+    XMLElem::WithElem(
+        name.to_owned(),
+        Vec::new(),
+        vec![XMLElem::Text(text.to_owned())],
+    )
+}
+
+fn node(name: &str, attrs: &[(&str, &str)], inner: Vec<XMLElem>) -> XMLElem {
+    XMLElem::WithElem(
+        name.to_owned(),
+        attrs
+            .iter()
+            .map(|(k, v)| Attr::Pair((*k).to_owned(), (*v).to_owned()))
+            .collect(),
+        inner,
+    )
+}
+
+fn mrow(mut inner: Vec<XMLElem>) -> XMLElem {
+    if inner.len() == 1 {
+        inner.pop().unwrap()
+    } else {
+        node("mrow", &[], inner)
+    }
+}
+
+fn greek(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "alpha" => "α",
+        "beta" => "β",
+        "gamma" => "γ",
+        "delta" => "δ",
+        "epsilon" => "ϵ",
+        "varepsilon" => "ε",
+        "zeta" => "ζ",
+        "eta" => "η",
+        "theta" => "θ",
+        "vartheta" => "ϑ",
+        "iota" => "ι",
+        "kappa" => "κ",
+        "lambda" => "λ",
+        "mu" => "μ",
+        "nu" => "ν",
+        "xi" => "ξ",
+        "pi" => "π",
+        "rho" => "ρ",
+        "sigma" => "σ",
+        "tau" => "τ",
+        "upsilon" => "υ",
+        "phi" => "ϕ",
+        "varphi" => "φ",
+        "chi" => "χ",
+        "psi" => "ψ",
+        "omega" => "ω",
+        "Gamma" => "Γ",
+        "Delta" => "Δ",
+        "Theta" => "Θ",
+        "Lambda" => "Λ",
+        "Xi" => "Ξ",
+        "Pi" => "Π",
+        "Sigma" => "Σ",
+        "Upsilon" => "Υ",
+        "Phi" => "Φ",
+        "Psi" => "Ψ",
+        "Omega" => "Ω",
+        "infty" => "∞",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "emptyset" => "∅",
+        "ell" => "ℓ",
+        "hbar" => "ℏ",
+        _ => return None,
+    })
+}
+
+fn operator(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "times" => "×",
+        "cdot" => "⋅",
+        "div" => "÷",
+        "pm" => "±",
+        "mp" => "∓",
+        "ast" => "∗",
+        "circ" => "∘",
+        "leq" | "le" => "≤",
+        "geq" | "ge" => "≥",
+        "neq" | "ne" => "≠",
+        "approx" => "≈",
+        "equiv" => "≡",
+        "sim" => "∼",
+        "simeq" => "≃",
+        "propto" => "∝",
+        "ll" => "≪",
+        "gg" => "≫",
+        "to" | "rightarrow" => "→",
+        "leftarrow" | "gets" => "←",
+        "leftrightarrow" => "↔",
+        "Rightarrow" | "implies" => "⇒",
+        "Leftarrow" => "⇐",
+        "Leftrightarrow" | "iff" => "⇔",
+        "mapsto" => "↦",
+        "in" => "∈",
+        "notin" => "∉",
+        "ni" => "∋",
+        "subset" => "⊂",
+        "supset" => "⊃",
+        "subseteq" => "⊆",
+        "supseteq" => "⊇",
+        "cup" => "∪",
+        "cap" => "∩",
+        "setminus" => "∖",
+        "forall" => "∀",
+        "exists" => "∃",
+        "neg" | "lnot" => "¬",
+        "land" | "wedge" => "∧",
+        "lor" | "vee" => "∨",
+        "oplus" => "⊕",
+        "otimes" => "⊗",
+        "mid" => "∣",
+        "ldots" | "dots" => "…",
+        "cdots" => "⋯",
+        "vdots" => "⋮",
+        "ddots" => "⋱",
+        "langle" => "⟨",
+        "rangle" => "⟩",
+        "lfloor" => "⌊",
+        "rfloor" => "⌋",
+        "lceil" => "⌈",
+        "rceil" => "⌉",
+        "|" => "‖",
+        "{" => "{",
+        "}" => "}",
+        "%" => "%",
+        "#" => "#",
+        "$" => "$",
+        "&" => "&",
+        "_" => "_",
+        _ => return None,
+    })
+}
+
+fn large_operator(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "sum" => "∑",
+        "prod" => "∏",
+        "coprod" => "∐",
+        "int" => "∫",
+        "iint" => "∬",
+        "iiint" => "∭",
+        "oint" => "∮",
+        "bigcup" => "⋃",
+        "bigcap" => "⋂",
+        "bigoplus" => "⨁",
+        "bigotimes" => "⨂",
+        _ => return None,
+    })
+}
+
+fn is_function(name: &str) -> bool {
+    [
+        "sin", "cos", "tan", "sec", "csc", "cot", "sinh", "cosh", "tanh", "arcsin", "arccos",
+        "arctan", "log", "ln", "lg", "exp", "det", "dim", "ker", "deg", "gcd", "arg", "Pr",
+    ]
+    .contains(&name)
+}
+
+// 添字を下や上に置く演算子
+fn has_limits(name: &str) -> bool {
+    [
+        "lim", "max", "min", "sup", "inf", "limsup", "liminf", "argmax", "argmin",
+    ]
+    .contains(&name)
+}
+
+fn space(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "," => "0.1667em",
+        ":" | ">" => "0.2222em",
+        ";" => "0.2778em",
+        " " => "0.25em",
+        "quad" => "1em",
+        "qquad" => "2em",
+        "!" => "-0.1667em",
+        _ => return None,
+    })
+}
+
+fn accent(name: &str) -> Option<(&'static str, bool)> {
+    // (記号, 上に付くか)
+    Some(match name {
+        "hat" | "widehat" => ("^", true),
+        "bar" | "overline" => ("¯", true),
+        "vec" => ("→", true),
+        "tilde" | "widetilde" => ("~", true),
+        "dot" => ("˙", true),
+        "ddot" => ("¨", true),
+        "underline" => ("_", false),
+        _ => return None,
+    })
+}
+
+fn variant(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "mathrm" | "operatorname" => "normal",
+        "mathbf" => "bold",
+        "mathit" => "italic",
+        "mathbb" => "double-struck",
+        "mathcal" => "script",
+        "mathfrak" => "fraktur",
+        "mathsf" => "sans-serif",
+        "mathtt" => "monospace",
+        _ => return None,
+    })
+}
+
+fn matrix_fences(env: &str) -> Option<(&'static str, &'static str)> {
+    Some(match env {
+        "matrix" | "aligned" | "array" => ("", ""),
+        "pmatrix" => ("(", ")"),
+        "bmatrix" => ("[", "]"),
+        "Bmatrix" => ("{", "}"),
+        "vmatrix" => ("|", "|"),
+        "Vmatrix" => ("‖", "‖"),
+        "cases" => ("{", ""),
+        _ => return None,
+    })
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<(Token, usize)>,
+    idx: usize,
+    display: bool,
+    loc: &'a Location,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, desc: &str) -> Error {
+        let offset = self
+            .tokens
+            .get(self.idx)
+            .map(|(_, offset)| *offset)
+            .unwrap_or_else(|| self.src.chars().count());
+        let before = self.src.chars().take(offset).collect::<String>();
+        let line = before.matches('\n').count() + 1;
+        let col = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+        Error::ProcessError {
+            loc: self.loc.to_owned(),
+            desc: format!("{} at line {}, column {} of the formula", desc, line, col),
+        }
+    }
+
+    fn skip_space(&mut self) {
+        while let Some((Token::Space, _)) = self.tokens.get(self.idx) {
+            self.idx += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<Token> {
+        self.skip_space();
+        self.tokens.get(self.idx).map(|(t, _)| t.clone())
+    }
+
+    fn bump(&mut self) -> Option<Token> {
+        let token = self.peek();
+        self.idx += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token, desc: &str) -> MResult<()> {
+        if self.peek() == Some(expected) {
+            self.idx += 1;
+            Ok(())
+        } else {
+            Err(self.error(desc))
+        }
+    }
+
+    // {}内の文字列をそのまま取り出す(\textや環境名用)
+    fn raw_group(&mut self) -> MResult<String> {
+        self.expect(Token::Open, "expected {")?;
+        let mut depth = 1;
+        let mut text = String::new();
+        while let Some((token, _)) = self.tokens.get(self.idx).cloned() {
+            self.idx += 1;
+            match token {
+                Token::Open => {
+                    depth += 1;
+                    text.push('{');
+                }
+                Token::Close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(text);
+                    }
+                    text.push('}');
+                }
+                Token::Macro(name) => {
+                    text.push('\\');
+                    text.push_str(&name);
+                }
+                Token::Sup => text.push('^'),
+                Token::Sub => text.push('_'),
+                Token::Amp => text.push('&'),
+                Token::Space => text.push(' '),
+                Token::Number(n) => text.push_str(&n),
+                Token::Char(c) => text.push(c),
+            }
+        }
+        Err(self.error("unclosed {"))
+    }
+
+    fn is_row_end(token: &Token) -> bool {
+        match token {
+            Token::Close | Token::Amp => true,
+            Token::Macro(name) => name == "\\" || name == "right" || name == "end",
+            _ => false,
+        }
+    }
+
+    fn row(&mut self) -> MResult<Vec<XMLElem>> {
+        let mut row = Vec::new();
+        while let Some(token) = self.peek() {
+            if Self::is_row_end(&token) {
+                break;
+            }
+            row.push(self.scripted()?);
+        }
+        Ok(row)
+    }
+
+    fn group(&mut self) -> MResult<XMLElem> {
+        self.expect(Token::Open, "expected {")?;
+        let row = self.row()?;
+        self.expect(Token::Close, "expected }")?;
+        Ok(mrow(row))
+    }
+
+    // 添字の引数は一文字か{}
+    fn argument(&mut self) -> MResult<XMLElem> {
+        match self.peek() {
+            Some(Token::Open) => self.group(),
+            Some(_) => Ok(self.atom()?.0),
+            None => Err(self.error("missing argument")),
+        }
+    }
+
+    fn scripted(&mut self) -> MResult<XMLElem> {
+        let (base, limits) = match self.peek() {
+            Some(Token::Sup) | Some(Token::Sub) => (node("mrow", &[], Vec::new()), false),
+            _ => self.atom()?,
+        };
+        let mut sub = None;
+        let mut sup = None;
+        loop {
+            match self.peek() {
+                Some(Token::Sub) if sub.is_none() => {
+                    self.idx += 1;
+                    sub = Some(self.argument()?);
+                }
+                Some(Token::Sup) if sup.is_none() => {
+                    self.idx += 1;
+                    sup = Some(self.argument()?);
+                }
+                Some(Token::Char('\'')) if sup.is_none() => {
+                    self.idx += 1;
+                    sup = Some(leaf("mo", "′"));
+                }
+                Some(Token::Sub) | Some(Token::Sup) => {
+                    return Err(self.error("double subscript or superscript"))
+                }
+                _ => break,
+            }
+        }
+        let under = limits && self.display;
+        Ok(match (sub, sup) {
+            (None, None) => base,
+            (Some(sub), None) if under => node("munder", &[], vec![base, sub]),
+            (None, Some(sup)) if under => node("mover", &[], vec![base, sup]),
+            (Some(sub), Some(sup)) if under => node("munderover", &[], vec![base, sub, sup]),
+            (Some(sub), None) => node("msub", &[], vec![base, sub]),
+            (None, Some(sup)) => node("msup", &[], vec![base, sup]),
+            (Some(sub), Some(sup)) => node("msubsup", &[], vec![base, sub, sup]),
+        })
+    }
+
+    fn delimiter(&mut self) -> MResult<XMLElem> {
+        let delim = match self.bump() {
+            Some(Token::Char('.')) => String::new(),
+            Some(Token::Char(c)) if "()[]|/".contains(c) => c.to_string(),
+            Some(Token::Macro(name)) => operator(&name)
+                .ok_or_else(|| self.error(&format!("invalid delimiter \\{}", name)))?
+                .to_owned(),
+            _ => return Err(self.error("invalid delimiter")),
+        };
+        Ok(node(
+            "mo",
+            &[("fence", "true"), ("stretchy", "true")],
+            vec![XMLElem::Text(delim)],
+        ))
+    }
+
+    fn environment(&mut self) -> MResult<XMLElem> {
+        let env = self.raw_group()?;
+        let (open, close) = matrix_fences(&env)
+            .ok_or_else(|| self.error(&format!("unsupported environment {}", env)))?;
+        if env == "array" {
+            // 列指定は読み捨てる
+            self.raw_group()?;
+        }
+        let mut rows = Vec::new();
+        let mut cells = Vec::new();
+        loop {
+            cells.push(node("mtd", &[], vec![mrow(self.row()?)]));
+            match self.bump() {
+                Some(Token::Amp) => (),
+                Some(Token::Macro(name)) if name == "\\" => {
+                    rows.push(node("mtr", &[], std::mem::take(&mut cells)));
+                }
+                Some(Token::Macro(name)) if name == "end" => {
+                    let end = self.raw_group()?;
+                    if end != env {
+                        return Err(
+                            self.error(&format!("\\begin{{{}}} ended by \\end{{{}}}", env, end))
+                        );
+                    }
+                    rows.push(node("mtr", &[], cells));
+                    break;
+                }
+                _ => return Err(self.error(&format!("unclosed environment {}", env))),
+            }
+        }
+        let align = match env.as_str() {
+            "cases" => "left",
+            "aligned" => "right left",
+            _ => "center",
+        };
+        let table = node("mtable", &[("columnalign", align)], rows);
+        let mut inner = Vec::new();
+        if !open.is_empty() {
+            inner.push(node(
+                "mo",
+                &[("fence", "true")],
+                vec![XMLElem::Text(open.to_owned())],
+            ));
+        }
+        inner.push(table);
+        if !close.is_empty() {
+            inner.push(node(
+                "mo",
+                &[("fence", "true")],
+                vec![XMLElem::Text(close.to_owned())],
+            ));
+        }
+        Ok(node("mrow", &[], inner))
+    }
+
+    fn command(&mut self, name: &str) -> MResult<(XMLElem, bool)> {
+        if let Some(c) = greek(name) {
+            return Ok((leaf("mi", c), false));
+        }
+        if let Some(op) = operator(name) {
+            return Ok((leaf("mo", op), false));
+        }
+        if let Some(op) = large_operator(name) {
+            return Ok((
+                node(
+                    "mo",
+                    &[("largeop", "true")],
+                    vec![XMLElem::Text(op.to_owned())],
+                ),
+                true,
+            ));
+        }
+        if is_function(name) {
+            return Ok((leaf("mi", name), false));
+        }
+        if has_limits(name) {
+            return Ok((leaf("mo", name), true));
+        }
+        if let Some(width) = space(name) {
+            return Ok((node("mspace", &[("width", width)], Vec::new()), false));
+        }
+        if let Some((mark, over)) = accent(name) {
+            let base = self.argument()?;
+            let mark = node(
+                "mo",
+                &[("stretchy", "true")],
+                vec![XMLElem::Text(mark.to_owned())],
+            );
+            return Ok(if over {
+                (
+                    node("mover", &[("accent", "true")], vec![base, mark]),
+                    false,
+                )
+            } else {
+                (
+                    node("munder", &[("accentunder", "true")], vec![base, mark]),
+                    false,
+                )
+            });
+        }
+        if let Some(variant) = variant(name) {
+            let text = self.raw_group()?;
+            return Ok((
+                node("mi", &[("mathvariant", variant)], vec![XMLElem::Text(text)]),
+                name == "operatorname",
+            ));
+        }
+        match name {
+            "frac" | "dfrac" | "tfrac" => {
+                let num = self.argument()?;
+                let den = self.argument()?;
+                Ok((node("mfrac", &[], vec![num, den]), false))
+            }
+            "binom" => {
+                let n = self.argument()?;
+                let k = self.argument()?;
+                Ok((
+                    node(
+                        "mrow",
+                        &[],
+                        vec![
+                            leaf("mo", "("),
+                            node("mfrac", &[("linethickness", "0")], vec![n, k]),
+                            leaf("mo", ")"),
+                        ],
+                    ),
+                    false,
+                ))
+            }
+            "sqrt" => {
+                if self.peek() == Some(Token::Char('[')) {
+                    self.idx += 1;
+                    let mut index = Vec::new();
+                    while self.peek() != Some(Token::Char(']')) {
+                        if self.peek().is_none() {
+                            return Err(self.error("unclosed ["));
+                        }
+                        index.push(self.scripted()?);
+                    }
+                    self.idx += 1;
+                    let base = self.argument()?;
+                    Ok((node("mroot", &[], vec![base, mrow(index)]), false))
+                } else {
+                    Ok((node("msqrt", &[], vec![self.argument()?]), false))
+                }
+            }
+            "text" | "mbox" => Ok((leaf("mtext", &self.raw_group()?), false)),
+            "left" => {
+                let open = self.delimiter()?;
+                let mut inner = vec![open];
+                inner.append(&mut self.row()?);
+                match self.bump() {
+                    Some(Token::Macro(name)) if name == "right" => (),
+                    _ => return Err(self.error("\\left without \\right")),
+                }
+                inner.push(self.delimiter()?);
+                Ok((node("mrow", &[], inner), false))
+            }
+            "begin" => Ok((self.environment()?, false)),
+            _ => Err(self.error(&format!("unsupported macro \\{}", name))),
+        }
+    }
+
+    fn atom(&mut self) -> MResult<(XMLElem, bool)> {
+        match self.bump() {
+            Some(Token::Open) => {
+                self.idx -= 1;
+                Ok((self.group()?, false))
+            }
+            Some(Token::Number(n)) => Ok((leaf("mn", &n), false)),
+            Some(Token::Char(c)) if c.is_alphabetic() => Ok((leaf("mi", &c.to_string()), false)),
+            Some(Token::Char(c)) => Ok((leaf("mo", &c.to_string()), false)),
+            Some(Token::Macro(name)) => self.command(&name),
+            _ => {
+                self.idx -= 1;
+                Err(self.error("unexpected token"))
+            }
+        }
+    }
+}
+
+// LaTeXの数式をMathMLの木に変換する。未対応のマクロはlocの位置でエラーにする
+fn parse(src: &str, display: bool, loc: &Location) -> MResult<XMLElem> {
+    let mut parser = Parser {
+        src,
+        tokens: tokenize(src),
+        idx: 0,
+        display,
+        loc,
+    };
+    let row = parser.row()?;
+    if parser.peek().is_some() {
+        return Err(parser.error("unexpected token"));
+    }
+    Ok(node("mrow", &[], row))
+}
+
+fn attr<'a>(attrs: &'a [Attr], key: &str) -> Option<&'a str> {
+    attrs.iter().find_map(|attr| match attr {
+        Attr::Pair(k, v) if k == key => Some(v.as_str()),
+        _ => None,
+    })
+}
+
+fn text_of(inner: &[XMLElem]) -> String {
+    inner
+        .iter()
+        .map(|elem| match elem {
+            XMLElem::Text(text) => text.clone(),
+            _ => String::new(),
+        })
+        .collect()
+}
+
+fn span(class: &str, inner: Vec<XMLElem>) -> XMLElem {
+    if class.is_empty() {
+        node("span", &[], inner)
+    } else {
+        node("span", &[("class", class)], inner)
+    }
+}
+
+// \mathbbなどはCSSで表現できないので数学用英数字記号に置き換える
+fn styled_char(c: char, variant: &str) -> char {
+    let exception = match (variant, c) {
+        ("double-struck", 'C') => '\u{2102}',
+        ("double-struck", 'H') => '\u{210D}',
+        ("double-struck", 'N') => '\u{2115}',
+        ("double-struck", 'P') => '\u{2119}',
+        ("double-struck", 'Q') => '\u{211A}',
+        ("double-struck", 'R') => '\u{211D}',
+        ("double-struck", 'Z') => '\u{2124}',
+        ("script", 'B') => '\u{212C}',
+        ("script", 'E') => '\u{2130}',
+        ("script", 'F') => '\u{2131}',
+        ("script", 'H') => '\u{210B}',
+        ("script", 'I') => '\u{2110}',
+        ("script", 'L') => '\u{2112}',
+        ("script", 'M') => '\u{2133}',
+        ("script", 'R') => '\u{211B}',
+        ("script", 'e') => '\u{212F}',
+        ("script", 'g') => '\u{210A}',
+        ("script", 'o') => '\u{2134}',
+        ("fraktur", 'C') => '\u{212D}',
+        ("fraktur", 'H') => '\u{210C}',
+        ("fraktur", 'I') => '\u{2111}',
+        ("fraktur", 'R') => '\u{211C}',
+        ("fraktur", 'Z') => '\u{2128}',
+        _ => c,
+    };
+    if exception != c {
+        return exception;
+    }
+    let (upper, lower, digit) = match variant {
+        "double-struck" => (0x1D538, 0x1D552, Some(0x1D7D8)),
+        "script" => (0x1D49C, 0x1D4B6, None),
+        "fraktur" => (0x1D504, 0x1D51E, None),
+        _ => return c,
+    };
+    let code = match c {
+        'A'..='Z' => upper + (c as u32 - 'A' as u32),
+        'a'..='z' => lower + (c as u32 - 'a' as u32),
+        '0'..='9' if digit.is_some() => digit.unwrap() + (c as u32 - '0' as u32),
+        _ => return c,
+    };
+    char::from_u32(code).unwrap_or(c)
+}
+
+// 括弧を伸ばすための目安となる高さ(行数)
+fn height(elem: &XMLElem) -> f32 {
+    match elem {
+        XMLElem::WithElem(name, _, inner) => {
+            let max = |elems: &[XMLElem]| elems.iter().map(height).fold(1.0, f32::max);
+            match name.as_str() {
+                "mfrac" => inner.iter().map(height).sum(),
+                "msqrt" | "mroot" => max(inner) + 0.2,
+                "munder" | "mover" | "munderover" => {
+                    max(&inner[..1]) + inner[1..].iter().map(|e| height(e) * 0.7).sum::<f32>()
+                }
+                "mtable" => inner
+                    .iter()
+                    .map(|row| match row {
+                        XMLElem::WithElem(_, _, cells) => max(cells),
+                        _ => 1.0,
+                    })
+                    .sum(),
+                _ => max(inner),
+            }
+        }
+        _ => 1.0,
+    }
+}
+
+// MathMLの木をspanとCSSによるレイアウトに置き換える
+fn layout(elem: XMLElem) -> XMLElem {
+    let (name, attrs, inner) = match elem {
+        XMLElem::WithElem(name, attrs, inner) => (name, attrs, inner),
+        XMLElem::Single(name, attrs) => (name, attrs, Vec::new()),
+        other => return other,
+    };
+    let text = text_of(&inner);
+    let mut inner = inner.into_iter();
+    let mut next = || {
+        inner
+            .next()
+            .map(layout)
+            .unwrap_or_else(|| span("", Vec::new()))
+    };
+    match name.as_str() {
+        "mi" => match attr(&attrs, "mathvariant") {
+            Some(variant @ ("double-struck" | "script" | "fraktur")) => span(
+                "math-rm",
+                vec![XMLElem::Text(
+                    text.chars().map(|c| styled_char(c, variant)).collect(),
+                )],
+            ),
+            Some("bold") => span("math-bf", vec![XMLElem::Text(text)]),
+            Some("italic") => span("math-i", vec![XMLElem::Text(text)]),
+            Some("sans-serif") => span("math-sf", vec![XMLElem::Text(text)]),
+            Some("monospace") => span("math-tt", vec![XMLElem::Text(text)]),
+            Some(_) => span("math-fn", vec![XMLElem::Text(text)]),
+            // 1文字の識別子だけを斜体にする
+            None if text.chars().count() == 1 => span("math-i", vec![XMLElem::Text(text)]),
+            None => span("math-fn", vec![XMLElem::Text(text)]),
+        },
+        "mn" | "mtext" => span("", vec![XMLElem::Text(text)]),
+        "mo" => {
+            let class = if attr(&attrs, "largeop").is_some() {
+                "math-op math-largeop"
+            } else if attr(&attrs, "fence").is_some() {
+                "math-fence"
+            } else if text == "," || text == ";" {
+                "math-punct"
+            } else {
+                "math-op"
+            };
+            span(class, vec![XMLElem::Text(text)])
+        }
+        "mspace" => node(
+            "span",
+            &[
+                ("class", "math-space"),
+                (
+                    "style",
+                    &format!("margin-left:{}", attr(&attrs, "width").unwrap_or("0")),
+                ),
+            ],
+            Vec::new(),
+        ),
+        "mfrac" => {
+            let class = if attr(&attrs, "linethickness") == Some("0") {
+                "math-frac math-frac-nobar"
+            } else {
+                "math-frac"
+            };
+            let num = next();
+            let den = next();
+            span(
+                class,
+                vec![span("math-num", vec![num]), span("math-den", vec![den])],
+            )
+        }
+        "msqrt" => span(
+            "math-sqrt",
+            vec![
+                span("math-radical", vec![XMLElem::Text("√".to_owned())]),
+                span("math-radicand", vec![next()]),
+            ],
+        ),
+        "mroot" => {
+            let base = next();
+            let index = next();
+            span(
+                "math-sqrt",
+                vec![
+                    span("math-root-index", vec![index]),
+                    span("math-radical", vec![XMLElem::Text("√".to_owned())]),
+                    span("math-radicand", vec![base]),
+                ],
+            )
+        }
+        "msub" | "msup" => {
+            let class = if name == "msub" {
+                "math-sub"
+            } else {
+                "math-sup"
+            };
+            let base = next();
+            let script = next();
+            span("", vec![base, span(class, vec![script])])
+        }
+        "msubsup" => {
+            let base = next();
+            let sub = next();
+            let sup = next();
+            span(
+                "",
+                vec![
+                    base,
+                    span(
+                        "math-subsup",
+                        vec![span("", vec![sup]), span("", vec![sub])],
+                    ),
+                ],
+            )
+        }
+        "mover" if attr(&attrs, "accent").is_some() => {
+            let base = next();
+            let mark = match next() {
+                XMLElem::WithElem(_, _, inner) => text_of(&inner),
+                _ => String::new(),
+            };
+            span(
+                "math-accent",
+                vec![base, span("math-accent-mark", vec![XMLElem::Text(mark)])],
+            )
+        }
+        "munder" if attr(&attrs, "accentunder").is_some() => span("math-underline", vec![next()]),
+        "munder" | "mover" | "munderover" => {
+            let base = next();
+            let first = next();
+            let (under, over) = match name.as_str() {
+                "munder" => (Some(first), None),
+                "mover" => (None, Some(first)),
+                _ => (Some(first), Some(next())),
+            };
+            let mut elems = Vec::new();
+            elems.extend(over.map(|over| span("math-limit", vec![over])));
+            elems.push(span("math-limit-base", vec![base]));
+            elems.extend(under.map(|under| span("math-limit", vec![under])));
+            span("math-underover", elems)
+        }
+        "mtable" => {
+            let aligns = attr(&attrs, "columnalign")
+                .unwrap_or("center")
+                .split_whitespace()
+                .map(|align| format!("math-td align-{}", align))
+                .collect::<Vec<_>>();
+            let rows = inner
+                .map(|row| match row {
+                    XMLElem::WithElem(_, _, cells) => span(
+                        "math-tr",
+                        cells
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, cell)| {
+                                let cell = match layout(cell) {
+                                    XMLElem::WithElem(_, _, inner) => inner,
+                                    other => vec![other],
+                                };
+                                span(&aligns[i % aligns.len()], cell)
+                            })
+                            .collect(),
+                    ),
+                    other => layout(other),
+                })
+                .collect();
+            span("math-table", rows)
+        }
+        _ => {
+            // mrow: 括弧は中身の高さに合わせて縦に伸ばす
+            let elems = inner.collect::<Vec<_>>();
+            let h = elems.iter().map(height).fold(1.0, f32::max);
+            span(
+                "",
+                elems
+                    .into_iter()
+                    .map(|elem| match elem {
+                        XMLElem::WithElem(name, attrs, inner)
+                            if name == "mo" && attr(&attrs, "fence").is_some() && h > 1.0 =>
+                        {
+                            node(
+                                "span",
+                                &[
+                                    ("class", "math-fence"),
+                                    ("style", &format!("transform:scaleY({:.1})", h)),
+                                ],
+                                vec![XMLElem::Text(text_of(&inner))],
+                            )
+                        }
+                        elem => layout(elem),
+                    })
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// LaTeXの数式をAMPでも使えるHTMLに変換する。
+/// AMPは`<math>`を許可しないので、MathMLの木をspanとCSS(index.cssの`.math`)で組む。
+/// 元のTeXはaria-labelに残す。未対応のマクロはlocの位置でエラーにする
+pub fn to_html(src: &str, display: bool, loc: &Location) -> Result<XMLElem, Error> {
+    let row = layout(parse(src, display, loc)?);
+    let inner = match row {
+        XMLElem::WithElem(_, _, inner) => inner,
+        other => vec![other],
+    };
+    Ok(node(
+        "span",
+        &[
+            ("class", if display { "math math-display" } else { "math" }),
+            ("role", "math"),
+            ("aria-label", src),
+        ],
+        inner,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn render(src: &str, display: bool) -> String {
+        format!("{}", parse(src, display, &Location::Generated).unwrap())
+    }
+
+    fn render_html(src: &str) -> String {
+        format!("{}", to_html(src, false, &Location::Generated).unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            render("x^2", false),
+            "<mrow ><msup ><mi >x</mi><mn >2</mn></msup></mrow>"
+        );
+        assert_eq!(
+            render("\\frac{a}{b}", false),
+            "<mrow ><mfrac ><mi >a</mi><mi >b</mi></mfrac></mrow>"
+        );
+        assert_eq!(
+            render("\\sum_{i=1}^n", true),
+            "<mrow ><munderover ><mo largeop=\"true\" >∑</mo><mrow ><mi >i</mi><mo >=</mo><mn >1</mn></mrow><mi >n</mi></munderover></mrow>"
+        );
+        assert_eq!(
+            render("\\sum_{i=1}^n", false),
+            "<mrow ><msubsup ><mo largeop=\"true\" >∑</mo><mrow ><mi >i</mi><mo >=</mo><mn >1</mn></mrow><mi >n</mi></msubsup></mrow>"
+        );
+        assert_eq!(
+            render("\\text{if } \\alpha", false),
+            "<mrow ><mtext >if </mtext><mi >α</mi></mrow>"
+        );
+        assert_eq!(
+            render("\\begin{pmatrix}1 & 0 \\\\ 0 & 1\\end{pmatrix}", false),
+            concat!(
+                "<mrow ><mrow ><mo fence=\"true\" >(</mo><mtable columnalign=\"center\" >",
+                "<mtr ><mtd ><mn >1</mn></mtd><mtd ><mn >0</mn></mtd></mtr>",
+                "<mtr ><mtd ><mn >0</mn></mtd><mtd ><mn >1</mn></mtd></mtr>",
+                "</mtable><mo fence=\"true\" >)</mo></mrow></mrow>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_to_html() {
+        assert_eq!(
+            render_html("x^2"),
+            concat!(
+                "<span class=\"math\" role=\"math\" aria-label=\"x^2\" >",
+                "<span ><span class=\"math-i\" >x</span>",
+                "<span class=\"math-sup\" ><span >2</span></span></span></span>"
+            )
+        );
+        assert_eq!(
+            render_html("\\frac{a}{b}"),
+            concat!(
+                "<span class=\"math\" role=\"math\" aria-label=\"\\frac{a}{b}\" >",
+                "<span class=\"math-frac\" >",
+                "<span class=\"math-num\" ><span class=\"math-i\" >a</span></span>",
+                "<span class=\"math-den\" ><span class=\"math-i\" >b</span></span>",
+                "</span></span>"
+            )
+        );
+        assert!(render_html("\\mathbb{R}").contains(">ℝ<"));
+        assert!(render_html("\\mathcal{A}").contains(">𝒜<"));
+        // 行列を囲む括弧は行数に合わせて伸ばす
+        assert!(render_html("\\begin{pmatrix}1 \\\\ 0\\end{pmatrix}")
+            .contains("<span class=\"math-fence\" style=\"transform:scaleY(2.0)\" >(</span>"));
+        // AMPで使えない<math>を出力しない
+        let html = to_html(
+            "\\sqrt[3]{x} + \\hat{y} + \\sum_{i=1}^n a_i^2 + \\begin{cases}1 & x \\\\ 0\\end{cases}",
+            true,
+            &Location::Generated,
+        )
+        .unwrap();
+        assert!(!format!("{}", html).contains("<m"));
+        assert!(crate::amp::check_elem(&html).is_empty());
+    }
+
+    #[test]
+    fn test_error() {
+        assert!(to_html("\\unknown{x}", false, &Location::Generated).is_err());
+        assert!(to_html("{x", false, &Location::Generated).is_err());
+        assert!(to_html("x}", false, &Location::Generated).is_err());
+        assert!(to_html("x^1^2", false, &Location::Generated).is_err());
+        assert!(to_html("\\left( x", false, &Location::Generated).is_err());
+    }
+}
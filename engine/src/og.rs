@@ -0,0 +1,268 @@
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use rusttype::{point, Font, Scale};
+use std::path::{Path, PathBuf};
+
+// SNSのカードに使われる大きさ(summary_large_image)
+pub const WIDTH: u32 = 1200;
+pub const HEIGHT: u32 = 630;
+const MARGIN: f32 = 80.0;
+const TITLE_SIZE: f32 = 64.0;
+const TITLE_LINES: usize = 3;
+const META_SIZE: f32 = 32.0;
+const SITE_NAME: &str = "namachan10777.dev";
+
+const BACKGROUND: [u8; 3] = [0xfd, 0xfd, 0xfd];
+const FOREGROUND: [u8; 3] = [0x22, 0x22, 0x22];
+const MUTED: [u8; 3] = [0x66, 0x66, 0x66];
+const ACCENT: [u8; 3] = [0x2e, 0x7d, 0x32];
+
+// 既定のフォント。欧文はDejaVu、日本語はJIS第1水準までのLXGW WenKaiで描く。
+// サイト側でog-image.fontを指定するとそちらを優先する
+const EMBEDDED_FONTS: [&[u8]; 2] = [
+    include_bytes!("../fonts/DejaVuSans-Bold.ttf"),
+    include_bytes!("../fonts/LXGWWenKai-Bold-JIS1.ttf"),
+];
+
+pub fn embedded_fonts() -> Vec<Font<'static>> {
+    EMBEDDED_FONTS
+        .iter()
+        .map(|font| Font::try_from_bytes(font).unwrap())
+        .collect()
+}
+
+// 記事ごとに生成するカード画像の内容
+#[derive(Debug, PartialEq)]
+pub struct Card {
+    pub title: String,
+    pub date: String,
+    pub categories: Vec<String>,
+}
+
+// articles/foo.html -> og/articles/foo.png
+pub fn card_path(article: &Path) -> PathBuf {
+    Path::new("og").join(article.with_extension("png"))
+}
+
+// 先頭から順にグリフを持つフォントを探す
+fn find_font<'a, 'f>(fonts: &'a [Font<'f>], c: char) -> Option<&'a Font<'f>> {
+    fonts.iter().find(|font| font.glyph(c).id().0 != 0)
+}
+
+fn advance(fonts: &[Font], scale: Scale, c: char) -> f32 {
+    find_font(fonts, c)
+        .map(|font| font.glyph(c).scaled(scale).h_metrics().advance_width)
+        .unwrap_or(0.0)
+}
+
+// 日本語は空白で区切られないので文字単位で折り返す。溢れた分は省略する
+fn wrap(fonts: &[Font], scale: Scale, text: &str, width: f32, max_lines: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    let mut x = 0.0;
+    for c in text.chars() {
+        let w = advance(fonts, scale, c);
+        if x + w > width && !lines.last().unwrap().is_empty() {
+            if lines.len() == max_lines {
+                let last = lines.last_mut().unwrap();
+                let ellipsis = advance(fonts, scale, '…');
+                while last.chars().map(|c| advance(fonts, scale, c)).sum::<f32>() + ellipsis > width
+                {
+                    last.pop();
+                }
+                last.push('…');
+                return lines;
+            }
+            lines.push(String::new());
+            x = 0.0;
+        }
+        if lines.last().unwrap().is_empty() && c.is_whitespace() {
+            continue;
+        }
+        lines.last_mut().unwrap().push(c);
+        x += w;
+    }
+    lines
+}
+
+fn blend(img: &mut RgbImage, x: i32, y: i32, color: [u8; 3], coverage: f32) {
+    if x < 0 || y < 0 || x >= img.width() as i32 || y >= img.height() as i32 {
+        return;
+    }
+    let pixel = img.get_pixel_mut(x as u32, y as u32);
+    for (dst, src) in pixel.0.iter_mut().zip(color.iter()) {
+        *dst = (*dst as f32 * (1.0 - coverage) + *src as f32 * coverage).round() as u8;
+    }
+}
+
+// (x, baseline)から描画し、描いた幅を返す
+fn draw_text(
+    img: &mut RgbImage,
+    fonts: &[Font],
+    scale: Scale,
+    (x, baseline): (f32, f32),
+    color: [u8; 3],
+    text: &str,
+) -> f32 {
+    let mut caret = x;
+    for c in text.chars() {
+        let font = match find_font(fonts, c) {
+            Some(font) => font,
+            None => continue,
+        };
+        let glyph = font.glyph(c).scaled(scale);
+        let advance = glyph.h_metrics().advance_width;
+        let glyph = glyph.positioned(point(caret, baseline));
+        if let Some(bb) = glyph.pixel_bounding_box() {
+            glyph.draw(|gx, gy, coverage| {
+                blend(
+                    img,
+                    bb.min.x + gx as i32,
+                    bb.min.y + gy as i32,
+                    color,
+                    coverage,
+                )
+            });
+        }
+        caret += advance;
+    }
+    caret - x
+}
+
+fn line_height(fonts: &[Font], scale: Scale) -> f32 {
+    let v = fonts[0].v_metrics(scale);
+    v.ascent - v.descent + v.line_gap
+}
+
+// どのフォントにもグリフが無い文字。豆腐になるのでカードを作る前に調べる
+pub fn missing_glyphs(fonts: &[Font], card: &Card) -> String {
+    card.title
+        .chars()
+        .chain(card.categories.iter().flat_map(|c| c.chars()))
+        .filter(|c| !c.is_whitespace() && find_font(fonts, *c).is_none())
+        .collect()
+}
+
+pub fn render(fonts: &[Font], card: &Card) -> Vec<u8> {
+    let mut img = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb(BACKGROUND));
+    // 左端のアクセント
+    for y in 0..HEIGHT {
+        for x in 0..16 {
+            img.put_pixel(x, y, Rgb(ACCENT));
+        }
+    }
+    let width = WIDTH as f32 - MARGIN * 2.0;
+    let title_scale = Scale::uniform(TITLE_SIZE);
+    let title_height = line_height(fonts, title_scale);
+    let mut baseline = MARGIN + fonts[0].v_metrics(title_scale).ascent;
+    for line in wrap(fonts, title_scale, &card.title, width, TITLE_LINES) {
+        draw_text(
+            &mut img,
+            fonts,
+            title_scale,
+            (MARGIN, baseline),
+            FOREGROUND,
+            &line,
+        );
+        baseline += title_height;
+    }
+    let meta_scale = Scale::uniform(META_SIZE);
+    let baseline = HEIGHT as f32 - MARGIN;
+    let mut x = MARGIN;
+    x += draw_text(
+        &mut img,
+        fonts,
+        meta_scale,
+        (x, baseline),
+        MUTED,
+        &card.date,
+    );
+    for category in &card.categories {
+        x += draw_text(
+            &mut img,
+            fonts,
+            meta_scale,
+            (x, baseline),
+            ACCENT,
+            &format!("  #{}", category),
+        );
+    }
+    let site_width = SITE_NAME
+        .chars()
+        .map(|c| advance(fonts, meta_scale, c))
+        .sum::<f32>();
+    draw_text(
+        &mut img,
+        fonts,
+        meta_scale,
+        (WIDTH as f32 - MARGIN - site_width, baseline),
+        FOREGROUND,
+        SITE_NAME,
+    );
+    let mut encoded = Vec::new();
+    DynamicImage::ImageRgb8(img)
+        .write_to(&mut encoded, ImageOutputFormat::Png)
+        .unwrap();
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::GenericImageView;
+
+    #[test]
+    fn test_wrap() {
+        let fonts = embedded_fonts();
+        let scale = Scale::uniform(TITLE_SIZE);
+        assert_eq!(wrap(&fonts, scale, "Hello", 1000.0, 3), vec!["Hello"]);
+        let lines = wrap(&fonts, scale, &"a".repeat(100), 400.0, 2);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].ends_with('…'));
+        assert!(lines.iter().all(|line| line
+            .chars()
+            .map(|c| advance(&fonts, scale, c))
+            .sum::<f32>()
+            <= 400.0));
+    }
+
+    #[test]
+    fn test_render() {
+        let card = Card {
+            title: "Per-article cards".to_owned(),
+            date: "2021-01-13".to_owned(),
+            categories: vec!["tech".to_owned()],
+        };
+        let png = render(&embedded_fonts(), &card);
+        let img = image::load_from_memory(&png).unwrap();
+        assert_eq!(img.dimensions(), (WIDTH, HEIGHT));
+        assert_eq!(
+            card_path(Path::new("articles/a.html")),
+            Path::new("og/articles/a.png")
+        );
+    }
+
+    #[test]
+    fn test_render_japanese() {
+        let fonts = embedded_fonts();
+        let card = Card {
+            title: "日本語のタイトル".to_owned(),
+            date: "2021-01-13".to_owned(),
+            categories: vec!["技術".to_owned()],
+        };
+        assert_eq!(missing_glyphs(&fonts, &card), "");
+        let img = image::load_from_memory(&render(&fonts, &card))
+            .unwrap()
+            .to_rgb8();
+        // タイトルの1行目に文字が描かれている
+        let baseline = MARGIN + fonts[0].v_metrics(Scale::uniform(TITLE_SIZE)).ascent;
+        let inked = (MARGIN as u32..WIDTH - MARGIN as u32)
+            .flat_map(|x| (MARGIN as u32..baseline as u32).map(move |y| (x, y)))
+            .filter(|(x, y)| img.get_pixel(*x, *y)[0] < 0x80)
+            .count();
+        assert!(inked > 1000);
+        let card = Card {
+            title: "한국어".to_owned(),
+            ..card
+        };
+        assert_eq!(missing_glyphs(&fonts, &card), "한국어");
+    }
+}
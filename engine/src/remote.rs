@@ -0,0 +1,84 @@
+use super::{images, Error};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+// 外部画像の大きさを記録するファイル。engine fetch-imagesで更新し、通常のビルドでは読むだけ
+pub const LOCKFILE: &str = "remote-images.lock";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct Size {
+    pub width: usize,
+    pub height: usize,
+}
+
+pub type Lock = BTreeMap<String, Size>;
+
+pub fn parse_lock(path: &Path, src: &[u8]) -> Result<Lock, Error> {
+    serde_json::from_slice(src).map_err(|e| Error::ConfigError {
+        path: path.to_owned(),
+        desc: e.to_string(),
+    })
+}
+
+pub fn serialize_lock(lock: &Lock) -> String {
+    // BTreeMapなので差分が出にくいようキー順に並ぶ
+    serde_json::to_string_pretty(lock).unwrap() + "\n"
+}
+
+// 画像を取得して大きさを調べる
+pub fn fetch_size(url: &str) -> Result<Size, Error> {
+    let error = |desc: String| Error::RemoteImageError {
+        url: url.to_owned(),
+        desc,
+    };
+    let response = ureq::get(url).call().map_err(|e| error(e.to_string()))?;
+    let content_type = response.content_type().to_owned();
+    let mut binary = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut binary)
+        .map_err(|e| error(e.to_string()))?;
+    let size = if content_type == "image/svg+xml" {
+        images::svg_size(&String::from_utf8_lossy(&binary))
+    } else if content_type == "image/avif" {
+        images::avif_size(&binary)
+    } else {
+        image::load_from_memory(&binary)
+            .ok()
+            .map(|img| image::GenericImageView::dimensions(&img))
+            .map(|(w, h)| (w as usize, h as usize))
+    };
+    size.map(|(width, height)| Size { width, height })
+        .ok_or_else(|| error(format!("cannot determine size of {}", content_type)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let path = Path::new(LOCKFILE);
+        let mut lock = Lock::new();
+        lock.insert(
+            "https://example.com/b.svg".to_owned(),
+            Size {
+                width: 90,
+                height: 20,
+            },
+        );
+        lock.insert(
+            "https://example.com/a.png".to_owned(),
+            Size {
+                width: 640,
+                height: 480,
+            },
+        );
+        let serialized = serialize_lock(&lock);
+        assert!(serialized.find("a.png").unwrap() < serialized.find("b.svg").unwrap());
+        assert_eq!(parse_lock(path, serialized.as_bytes()).unwrap(), lock);
+        assert!(parse_lock(path, b"[]").is_err());
+    }
+}
@@ -85,9 +85,33 @@ impl XML {
 }
 
 impl XMLElem {
+    // data-nosnippetが付いた要素はdescriptionなどの抽出対象から外す
+    pub fn nosnippet(self) -> Self {
+        match self {
+            XMLElem::Single(name, mut attrs) => {
+                attrs.push(Attr::Single("data-nosnippet".to_owned()));
+                XMLElem::Single(name, attrs)
+            }
+            XMLElem::WithElem(name, mut attrs, inner) => {
+                attrs.push(Attr::Single("data-nosnippet".to_owned()));
+                XMLElem::WithElem(name, attrs, inner)
+            }
+            other => other,
+        }
+    }
+
     pub fn extract_string(&self) -> String {
         match self {
             XMLElem::Single(_, _) => String::new(),
+            XMLElem::WithElem(_, attrs, _)
+                if attrs
+                    .iter()
+                    .any(|attr| matches!(attr, Attr::Single(name) if name == "data-nosnippet")) =>
+            {
+                String::new()
+            }
+            // ルビは親文字だけを残す
+            XMLElem::WithElem(name, _, _) if name == "rt" || name == "rp" => String::new(),
             XMLElem::WithElem(_, _, inner) => inner
                 .iter()
                 .map(|inner| inner.extract_string())
@@ -433,4 +457,20 @@ mod test {
             )
         );
     }
+    #[test]
+    fn test_extract_string() {
+        let xml = xml!(p [] [
+            xml!("本文".to_owned()),
+            xml!(sup [] [xml!("1".to_owned())]).nosnippet(),
+            xml!(span [] [xml!("続き".to_owned())])
+        ]);
+        assert_eq!(xml.extract_string(), "本文続き");
+        let xml = xml!(ruby [] [
+            xml!("振仮名".to_owned()),
+            xml!(rp [] [xml!("(".to_owned())]),
+            xml!(rt [] [xml!("ふりがな".to_owned())]),
+            xml!(rp [] [xml!(")".to_owned())])
+        ]);
+        assert_eq!(xml.extract_string(), "振仮名");
+    }
 }